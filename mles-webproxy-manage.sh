@@ -1,7 +1,7 @@
 #!/bin/sh 
 
 start() {
-  exec /home/ubuntu/www/mles-webproxy/target/release/mles-webproxy /home/ubuntu/www/mles-webproxy/static jq-rs@mles.io mles.io 127.0.0.1:8077
+  exec /home/ubuntu/www/mles-webproxy/target/release/mles-webproxy --config /home/ubuntu/www/mles-webproxy/mles-webproxy.toml
 }
 
 stop() {
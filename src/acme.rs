@@ -0,0 +1,636 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ *  Copyright (C) 2020  Mles developers
+ */
+use acme_lib::persist::{Persist, PersistKey, PersistKind};
+use base64::{encode_config, URL_SAFE_NO_PAD};
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use tokio_rustls::rustls::{sign, ResolvesServerCert, SignatureScheme};
+use tokio_rustls::webpki;
+use warp::Filter;
+
+use crate::config::{AcmeChallenge, AcmeConfig, Config, DnsConfig, Host};
+use crate::storage::{StatePersist, Storage};
+use crate::{dns, tls};
+use crate::{expire_time, AMONTH};
+
+const ES256_LEN: usize = 32;
+/// Directory of the challenges shared with the `cert renew` command, within the state dir
+const CHALLENGE_DIR: &str = "acme-challenge";
+
+/// HTTP-01 challenge proofs by token and TLS-ALPN-01 challenge certificates
+/// by domain, shared by `request_cert` and the listeners.
+///
+/// The challenges are also kept as files in the challenge directory, so the
+/// running service answers the challenges of `cert renew` too.
+#[derive(Clone)]
+pub struct ChallengeStore {
+    storage: Storage,
+    dir: PathBuf,
+    proofs: Arc<RwLock<HashMap<String, String>>>,
+    certs: Arc<RwLock<HashMap<String, sign::CertifiedKey>>>,
+}
+
+impl ChallengeStore {
+    pub fn new(storage: &Storage) -> Self {
+        ChallengeStore {
+            storage: storage.clone(),
+            dir: storage.path(CHALLENGE_DIR),
+            proofs: Arc::new(RwLock::new(HashMap::new())),
+            certs: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub fn insert(&self, token: &str, proof: &str) {
+        let mut proofs = self.proofs.write().unwrap();
+        proofs.insert(token.to_string(), proof.to_string());
+        self.write_file(token, proof.as_bytes(), false);
+    }
+
+    pub fn remove(&self, token: &str) {
+        let mut proofs = self.proofs.write().unwrap();
+        proofs.remove(token);
+        self.remove_file(token);
+    }
+
+    fn get(&self, token: &str) -> Option<String> {
+        let proofs = self.proofs.read().unwrap();
+        match proofs.get(token) {
+            Some(proof) => Some(proof.clone()),
+            None => self
+                .read_file(token, false)
+                .and_then(|proof| String::from_utf8(proof).ok()),
+        }
+    }
+
+    /// Takes the challenge certificate and key in PEM format into use for the domain.
+    pub fn insert_cert(&self, domain: &str, pem: &str) -> Result<(), String> {
+        let cert = tls::parse_certified_key(pem.as_bytes())?;
+        let mut certs = self.certs.write().unwrap();
+        certs.insert(domain.to_string(), cert);
+        /* The certificate comes with its private key */
+        self.write_file(&alpn_file(domain), pem.as_bytes(), true);
+        Ok(())
+    }
+
+    pub fn remove_cert(&self, domain: &str) {
+        let mut certs = self.certs.write().unwrap();
+        certs.remove(domain);
+        self.remove_file(&alpn_file(domain));
+    }
+
+    fn get_cert(&self, domain: &str) -> Option<sign::CertifiedKey> {
+        let certs = self.certs.read().unwrap();
+        match certs.get(domain) {
+            Some(cert) => Some(cert.clone()),
+            None => {
+                let pem = self.read_file(&alpn_file(domain), true)?;
+                tls::parse_certified_key(&pem).ok()
+            }
+        }
+    }
+
+    fn write_file(&self, name: &str, content: &[u8], private: bool) {
+        let path = self.dir.join(name);
+        let res = std::fs::create_dir_all(&self.dir).and_then(|_| {
+            if private {
+                self.storage.write_private(&path, content)
+            } else {
+                std::fs::write(&path, content)
+            }
+        });
+        if let Err(err) = res {
+            println!("Cannot write challenge {}: {}", name, err);
+        }
+    }
+
+    fn remove_file(&self, name: &str) {
+        let _ = std::fs::remove_file(self.dir.join(name));
+    }
+
+    fn read_file(&self, name: &str, private: bool) -> Option<Vec<u8>> {
+        /* The names come from clients, allow nothing but a plain file name */
+        let valid = name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+        if !valid || name.starts_with('.') {
+            return None;
+        }
+        let path = self.dir.join(name);
+        if private {
+            self.storage.read_private(path).ok()
+        } else {
+            std::fs::read(path).ok()
+        }
+    }
+
+    /// Route serving the proofs at /.well-known/acme-challenge/<token>
+    pub fn route(&self) -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+        let challenges = self.clone();
+        warp::path!(".well-known" / "acme-challenge" / String).and_then(move |token: String| {
+            match challenges.get(&token) {
+                Some(proof) => Ok(proof),
+                None => Err(warp::reject::not_found()),
+            }
+        })
+    }
+}
+
+fn alpn_file(domain: &str) -> String {
+    format!("{}.tls-alpn.pem", domain)
+}
+
+/// Resolves the TLS-ALPN-01 challenge certificate by the server name.
+impl ResolvesServerCert for ChallengeStore {
+    fn resolve(
+        &self,
+        server_name: Option<webpki::DNSNameRef>,
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<sign::CertifiedKey> {
+        let name: &str = server_name?.into();
+        self.get_cert(&name.to_lowercase())
+    }
+}
+
+/// External Account Binding credentials from the ACME CA.
+#[derive(Clone)]
+pub struct Eab {
+    pub kid: String,
+    pub hmac_key: Vec<u8>,
+}
+
+/// Persistence which registers a new ACME account with External Account
+/// Binding before acme-lib looks up the account key.
+///
+/// acme-lib does not support EAB, but a CA returns the existing account
+/// for an already registered key, so acme-lib can carry on from there.
+#[derive(Clone)]
+pub struct AccountPersist<P: Persist> {
+    inner: P,
+    directory: String,
+    email: String,
+    eab: Option<Eab>,
+}
+
+impl<P: Persist> AccountPersist<P> {
+    pub fn new(inner: P, acme: &AcmeConfig, email: &str) -> Self {
+        AccountPersist {
+            inner,
+            directory: acme.directory_url().to_string(),
+            email: email.to_string(),
+            eab: acme.eab(),
+        }
+    }
+}
+
+impl<P: Persist> Persist for AccountPersist<P> {
+    fn put(&self, key: &PersistKey, value: &[u8]) -> acme_lib::Result<()> {
+        self.inner.put(key, value)
+    }
+
+    fn get(&self, key: &PersistKey) -> acme_lib::Result<Option<Vec<u8>>> {
+        let value = self.inner.get(key)?;
+        if value.is_some() {
+            return Ok(value);
+        }
+        let eab = match (&key.kind, &self.eab) {
+            (PersistKind::AccountPrivateKey, Some(eab)) => eab,
+            _ => return Ok(value),
+        };
+        println!("Registering ACME account with external account binding..");
+        let pem = register_eab_account(&self.directory, &self.email, eab)?;
+        self.inner.put(key, &pem)?;
+        Ok(Some(pem))
+    }
+}
+
+fn other_err<E: std::fmt::Display>(err: E) -> acme_lib::Error {
+    acme_lib::Error::Other(err.to_string())
+}
+
+fn b64url(data: &[u8]) -> String {
+    encode_config(data, URL_SAFE_NO_PAD)
+}
+
+fn get_json(url: &str) -> acme_lib::Result<Value> {
+    let resp = ureq::get(url).call();
+    if !resp.ok() {
+        return Err(other_err(format!("GET {} failed: {}", url, resp.status())));
+    }
+    let body = resp.into_string()?;
+    serde_json::from_str(&body).map_err(other_err)
+}
+
+fn new_nonce(url: &str) -> acme_lib::Result<String> {
+    let resp = ureq::head(url).call();
+    match resp.header("Replay-Nonce") {
+        Some(nonce) => Ok(nonce.to_string()),
+        None => Err(other_err(format!("No Replay-Nonce from {}", url))),
+    }
+}
+
+fn jwk(key: &EcKey<Private>) -> acme_lib::Result<Value> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).map_err(other_err)?;
+    let mut ctx = BigNumContext::new().map_err(other_err)?;
+    let mut x = BigNum::new().map_err(other_err)?;
+    let mut y = BigNum::new().map_err(other_err)?;
+    key.public_key()
+        .affine_coordinates_gfp(&group, &mut x, &mut y, &mut ctx)
+        .map_err(other_err)?;
+    Ok(json!({
+        "crv": "P-256",
+        "kty": "EC",
+        "x": b64url(&pad(x.to_vec())),
+        "y": b64url(&pad(y.to_vec())),
+    }))
+}
+
+/// Left pads a big endian integer to the ES256 component size.
+fn pad(mut value: Vec<u8>) -> Vec<u8> {
+    while value.len() < ES256_LEN {
+        value.insert(0, 0);
+    }
+    value
+}
+
+fn sign_es256(key: &EcKey<Private>, data: &[u8]) -> acme_lib::Result<Vec<u8>> {
+    let digest = openssl::sha::sha256(data);
+    let sig = EcdsaSig::sign(&digest, key).map_err(other_err)?;
+    let mut rs = pad(sig.r().to_vec());
+    rs.extend(pad(sig.s().to_vec()));
+    Ok(rs)
+}
+
+fn sign_hs256(hmac_key: &[u8], data: &[u8]) -> acme_lib::Result<Vec<u8>> {
+    let pkey = PKey::hmac(hmac_key).map_err(other_err)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &pkey).map_err(other_err)?;
+    signer.update(data).map_err(other_err)?;
+    signer.sign_to_vec().map_err(other_err)
+}
+
+/// Returns the externalAccountBinding of a newAccount request, a JWS of the
+/// account key MACed with the EAB key (RFC 8555 7.3.4).
+fn eab_binding(eab: &Eab, jwk: &Value, url: &str) -> acme_lib::Result<Value> {
+    let protected = b64url(
+        json!({ "alg": "HS256", "kid": eab.kid, "url": url })
+            .to_string()
+            .as_bytes(),
+    );
+    let payload = b64url(jwk.to_string().as_bytes());
+    let signature = sign_hs256(
+        &eab.hmac_key,
+        format!("{}.{}", protected, payload).as_bytes(),
+    )?;
+    Ok(json!({
+        "protected": protected,
+        "payload": payload,
+        "signature": b64url(&signature),
+    }))
+}
+
+/// Creates a new account key and registers it with the EAB credentials.
+/// Returns the account key in PEM format.
+fn register_eab_account(directory: &str, email: &str, eab: &Eab) -> acme_lib::Result<Vec<u8>> {
+    let dir = get_json(directory)?;
+    let new_account = dir["newAccount"]
+        .as_str()
+        .ok_or_else(|| other_err("No newAccount in directory"))?;
+    let new_nonce_url = dir["newNonce"]
+        .as_str()
+        .ok_or_else(|| other_err("No newNonce in directory"))?;
+
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).map_err(other_err)?;
+    let key = EcKey::generate(&group).map_err(other_err)?;
+    let jwk = jwk(&key)?;
+
+    let payload = b64url(
+        json!({
+            "termsOfServiceAgreed": true,
+            "contact": [format!("mailto:{}", email)],
+            "externalAccountBinding": eab_binding(eab, &jwk, new_account)?,
+        })
+        .to_string()
+        .as_bytes(),
+    );
+    let protected = b64url(
+        json!({
+            "alg": "ES256",
+            "jwk": jwk,
+            "nonce": new_nonce(new_nonce_url)?,
+            "url": new_account,
+        })
+        .to_string()
+        .as_bytes(),
+    );
+    let signature = sign_es256(&key, format!("{}.{}", protected, payload).as_bytes())?;
+    let body = json!({
+        "protected": protected,
+        "payload": payload,
+        "signature": b64url(&signature),
+    });
+
+    let resp = ureq::post(new_account)
+        .set("Content-Type", "application/jose+json")
+        .send_string(&body.to_string());
+    if !resp.ok() {
+        let status = resp.status();
+        let problem = resp.into_string().unwrap_or_default();
+        return Err(other_err(format!(
+            "EAB account registration failed: {} {}",
+            status, problem
+        )));
+    }
+    let pkey = PKey::from_ec_key(key).map_err(other_err)?;
+    pkey.private_key_to_pem_pkcs8().map_err(other_err)
+}
+
+type Account = acme_lib::Account<AccountPersist<StatePersist>>;
+
+fn account(config: &Config) -> Result<Account, acme_lib::Error> {
+    let email = &config.tls.email;
+
+    // Use directory "letsencrypt-staging" for dev/testing.
+    let url = acme_lib::DirectoryUrl::Other(config.acme.directory_url());
+    println!("ACME directory: {}", config.acme.directory_url());
+
+    // Save/load keys and certificates to the state dir. A new account
+    // is registered with external account binding if configured.
+    let storage = Storage::new(&config.storage);
+    let persist = AccountPersist::new(StatePersist::new(&storage), &config.acme, email);
+
+    // Create a directory entrypoint.
+    let dir = acme_lib::Directory::from_url(persist, url)?;
+
+    // Reads the private account key from persistence, or
+    // creates a new one before accessing the API to establish
+    // that it's there.
+    dir.account(email)
+}
+
+pub fn request_cert(
+    config: &Config,
+    challenges: &ChallengeStore,
+    host: &Host,
+    force: bool,
+) -> Result<(), acme_lib::Error> {
+    let cert_time = expire_time(&host.pem_name);
+
+    println!("Time to expire {} {:#?}", host.domain, cert_time);
+
+    if AMONTH > cert_time || force {
+        println!("Less than month or forced, renewing..");
+
+        let acc = account(config)?;
+
+        // Order a new TLS certificate for a domain and its alternative names.
+        let alt_names: Vec<&str> = host.alt_names.iter().map(|name| name.as_str()).collect();
+        let mut ord_new = acc.new_order(&host.domain, &alt_names)?;
+
+        // Run forever on the current thread, serving using TLS to serve on the given domain.
+        // Serves port 80 and port 443.  It obtains TLS credentials from
+        // `letsencrypt.org` and then serves up the site on port 443. It
+        // also serves redirects on port 80.
+        // If the ownership of the domain(s) have already been authorized
+        // in a previous order, you might be able to skip validation. The
+        // ACME API provider decides.
+        let ord_csr = loop {
+            // are we done?
+            if let Some(ord_csr) = ord_new.confirm_validations() {
+                break ord_csr;
+            }
+
+            // Get the possible authorizations, one for each name
+            // which is not authorized yet.
+            let auths = ord_new.authorizations()?;
+            let auths: Vec<_> = auths.iter().filter(|auth| auth.need_challenge()).collect();
+
+            match config.acme.challenge {
+                AcmeChallenge::Http01 => {
+                    for auth in auths {
+                        http_challenge(challenges, auth)?;
+                    }
+                }
+                AcmeChallenge::TlsAlpn01 => {
+                    for auth in auths {
+                        tls_alpn_challenge(challenges, auth)?;
+                    }
+                }
+                AcmeChallenge::Dns01 => dns_challenge(&config.acme.dns, &auths)?,
+            }
+
+            // Update the state against the ACME API.
+            ord_new.refresh()?;
+        };
+
+        // Ownership is proven. Create a private/public key pair for the
+        // certificate. These are provided for convenience, you can
+        // provide your own keypair instead if you want.
+        let pkey_pri = acme_lib::create_p384_key();
+
+        // Submit the CSR. This causes the ACME provider to enter a state
+        // of "processing" that must be polled until the certificate is
+        // either issued or rejected. Again we poll for the status change.
+        let ord_cert = ord_csr.finalize_pkey(pkey_pri, 5000)?;
+
+        // Now download the certificate. Also stores the cert in the
+        // persistence.
+        let cert = ord_cert.download_and_save_cert()?;
+        let storage = Storage::new(&config.storage);
+        std::fs::write(&host.pem_name, cert.certificate())?;
+        storage.write_private(&host.key_name, cert.private_key().as_bytes())?;
+    }
+    Ok(())
+}
+
+fn http_challenge<P: Persist>(
+    challenges: &ChallengeStore,
+    auth: &acme_lib::order::Auth<P>,
+) -> Result<(), acme_lib::Error> {
+    // For HTTP, the challenge is a text file that needs to
+    // be accessible over the web for the domain(s) you are
+    // trying to get a certificate for:
+    //
+    // http://mydomain.io/.well-known/acme-challenge/<token>
+    let chall = auth.http_challenge();
+
+    // The token is the filename.
+    let token = chall.http_token().to_string();
+    // The proof is the contents of the file
+    let proof = chall.http_proof();
+
+    // The always running ACME listeners serve the proof
+    // from the challenge store.
+    challenges.insert(&token, &proof);
+
+    // After the file is accessible from the web, the calls
+    // this to tell the ACME API to start checking the
+    // existence of the proof.
+    //
+    // The order at ACME will change status to either
+    // confirm ownership of the domain, or fail due to the
+    // not finding the proof. To see the change, we poll
+    // the API with 5000 milliseconds wait between.
+    let res = chall.validate(5000);
+    // Now the proof is not needed anymore
+    challenges.remove(&token);
+    res
+}
+
+fn tls_alpn_challenge<P: Persist>(
+    challenges: &ChallengeStore,
+    auth: &acme_lib::order::Auth<P>,
+) -> Result<(), acme_lib::Error> {
+    // For TLS-ALPN, the proof is a self-signed certificate
+    // served on port 443 to the connections which offer the
+    // acme-tls/1 protocol only.
+    let domain = auth.domain_name().to_lowercase();
+    let chall = auth.tls_alpn_challenge();
+    let pem = tls::acme_challenge_cert(&domain, &chall.tls_alpn_proof()).map_err(other_err)?;
+    challenges.insert_cert(&domain, &pem).map_err(other_err)?;
+
+    let res = chall.validate(5000);
+    challenges.remove_cert(&domain);
+    res
+}
+
+fn dns_challenge<P: Persist>(
+    config: &DnsConfig,
+    auths: &[&acme_lib::order::Auth<P>],
+) -> Result<(), acme_lib::Error> {
+    // For DNS, the proof is a TXT record of the name
+    // _acme-challenge.<domain>. A wildcard and its base domain
+    // have the same name, so all records are published before
+    // any of them is validated.
+    let solver = dns::solver(config);
+    let records: Vec<(String, String)> = auths
+        .iter()
+        .map(|auth| {
+            let name = format!("_acme-challenge.{}", auth.domain_name());
+            (name, auth.dns_challenge().dns_proof())
+        })
+        .collect();
+    let mut res = Ok(());
+    let mut published = Vec::new();
+    for (name, proof) in records.iter() {
+        println!("Publishing TXT record {}", name);
+        res = solver.set(name, proof).map_err(other_err);
+        if res.is_err() {
+            break;
+        }
+        published.push((name, proof));
+    }
+
+    if res.is_ok() {
+        // Give the record time to reach all name servers
+        // before the ACME API looks for it.
+        std::thread::sleep(config.propagation_duration());
+        for auth in auths {
+            res = auth.dns_challenge().validate(5000);
+            if res.is_err() {
+                break;
+            }
+        }
+    }
+
+    // Now the records are not needed anymore
+    for (name, proof) in published {
+        if let Err(err) = solver.unset(name, proof) {
+            println!("Removing TXT record {} failed: {}", name, err);
+        }
+    }
+    res
+}
+
+/// Revokes the certificate of the host with the account which requested it.
+pub fn revoke_cert(
+    config: &Config,
+    host: &Host,
+    reason: acme_lib::RevocationReason,
+) -> Result<(), acme_lib::Error> {
+    let acc = account(config)?;
+    let cert = match acc.certificate(&host.domain)? {
+        Some(cert) => cert,
+        None => return Err(other_err(format!("no certificate for {}", host.domain))),
+    };
+    acc.revoke_certificate(&cert, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::decode_config;
+
+    fn b64url_json(data: &Value) -> Value {
+        let bytes = decode_config(data.as_str().unwrap(), URL_SAFE_NO_PAD).unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[test]
+    fn hs256_vector() {
+        /* RFC 7515 A.1 */
+        let key = decode_config(
+            "AyM1SysPpbyDfgZld3umj1qzKObwVMkoqQ-EstJQLr_T-1qS0gZH75aKtMN3Yj0iPS4hcgUuTwjAzZr1Z9CAow",
+            URL_SAFE_NO_PAD,
+        )
+        .unwrap();
+        let input = "eyJ0eXAiOiJKV1QiLA0KICJhbGciOiJIUzI1NiJ9.eyJpc3MiOiJqb2UiLA0KICJleHAiOjEzMDA4MTkzODAsDQogImh0dHA6Ly9leGFtcGxlLmNvbS9pc19yb290Ijp0cnVlfQ";
+        let mac = sign_hs256(&key, input.as_bytes()).unwrap();
+        assert_eq!(b64url(&mac), "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk");
+    }
+
+    #[test]
+    fn eab_binding_jws() {
+        let eab = Eab {
+            kid: "kid-1".to_string(),
+            hmac_key: b"0123456789abcdef0123456789abcdef".to_vec(),
+        };
+        let jwk = json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": "f83OJ3D2xF1Bg8vub9tLe1gHMzV76e8Tus9uPHvRVEU",
+            "y": "x_FEzRu9m36HLN_tue659LNpXW6pCyStikYjKIWI5a0",
+        });
+        let url = "https://ca.example/acme/new-acct";
+        let binding = eab_binding(&eab, &jwk, url).unwrap();
+
+        assert_eq!(
+            b64url_json(&binding["protected"]),
+            json!({ "alg": "HS256", "kid": "kid-1", "url": url })
+        );
+        assert_eq!(b64url_json(&binding["payload"]), jwk);
+        assert_eq!(
+            binding["protected"],
+            "eyJhbGciOiJIUzI1NiIsImtpZCI6ImtpZC0xIiwidXJsIjoiaHR0cHM6Ly9jYS5leGFtcGxlL2FjbWUvbmV3LWFjY3QifQ"
+        );
+        assert_eq!(
+            binding["signature"],
+            "TNTZJblu9AgjIFbCyd5bdlSFtccyc51nD1Ch3ad82_Q"
+        );
+    }
+
+    #[test]
+    fn jwk_coordinates_are_padded() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        for _ in 0..8 {
+            let key = EcKey::generate(&group).unwrap();
+            let jwk = jwk(&key).unwrap();
+            for coordinate in &["x", "y"] {
+                let value = jwk[coordinate].as_str().unwrap();
+                let value = decode_config(value, URL_SAFE_NO_PAD).unwrap();
+                assert_eq!(value.len(), ES256_LEN);
+            }
+        }
+    }
+}
@@ -0,0 +1,197 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ *  Copyright (C) 2020  Mles developers
+ */
+use acme_lib::RevocationReason;
+
+use crate::acme::{self, ChallengeStore};
+use crate::config::{Config, Host, TlsMode};
+use crate::storage::Storage;
+use crate::{time_to_expiration, ADAY, AMONTH};
+
+const CERT_USAGE: &str = "Usage: mles-webproxy [--config <config-file>] cert <command>
+Commands:
+    status                              Show the expiry of the certificates
+    renew [--force] [--domain <name>]   Renew the certificates which are due, or all with --force
+    revoke [--reason <reason>] --domain <name>|--all
+                                        Revoke the certificate of the domain, or all with --all,
+                                        reason is one of unspecified, key-compromise, superseded
+                                        or cessation-of-operation";
+
+/// Revocation reasons accepted by `cert revoke`. Unlike the reason of
+/// acme-lib it is `Copy`, so one parsed reason serves every host.
+#[derive(Clone, Copy)]
+enum Reason {
+    Unspecified,
+    KeyCompromise,
+    Superseded,
+    CessationOfOperation,
+}
+
+impl From<Reason> for RevocationReason {
+    fn from(reason: Reason) -> Self {
+        match reason {
+            Reason::Unspecified => RevocationReason::Unspecified,
+            Reason::KeyCompromise => RevocationReason::KeyCompromise,
+            Reason::Superseded => RevocationReason::Superseded,
+            Reason::CessationOfOperation => RevocationReason::CessationOfOperation,
+        }
+    }
+}
+
+/// Runs a certificate management command and returns the exit code.
+///
+/// Renewed certificates are picked up by the running service, which also
+/// answers the HTTP-01 and TLS-ALPN-01 challenges of the renewal.
+pub fn run(config: &Config, args: &[String]) -> i32 {
+    if config.tls.mode == TlsMode::Off {
+        println!("TLS is off, there are no certificates to manage");
+        return 1;
+    }
+    let mut force = false;
+    let mut all = false;
+    let mut domain = None;
+    let mut reason = Reason::Unspecified;
+    let command = args.first().map(|command| command.as_str());
+    let mut options = args.iter().skip(1);
+    while let Some(option) = options.next() {
+        match (option.as_str(), command) {
+            ("--force", Some("renew")) => force = true,
+            ("--all", Some("revoke")) => all = true,
+            ("--domain", Some("renew")) | ("--domain", Some("revoke")) => match options.next() {
+                Some(name) => domain = Some(name.clone()),
+                None => {
+                    println!("{}", CERT_USAGE);
+                    return 1;
+                }
+            },
+            ("--reason", Some("revoke")) => match options.next() {
+                Some(name) => match parse_reason(name) {
+                    Some(parsed) => reason = parsed,
+                    None => {
+                        println!("{}", CERT_USAGE);
+                        return 1;
+                    }
+                },
+                None => {
+                    println!("{}", CERT_USAGE);
+                    return 1;
+                }
+            },
+            _ => {
+                println!("{}", CERT_USAGE);
+                return 1;
+            }
+        }
+    }
+
+    /* Revocation cannot be undone, so it never defaults to every host */
+    if command == Some("revoke") && domain.is_some() == all {
+        println!("{}", CERT_USAGE);
+        return 1;
+    }
+
+    let hosts: Vec<Host> = config
+        .hosts()
+        .into_iter()
+        .filter(|host| {
+            domain
+                .as_ref()
+                .map_or(true, |domain| &host.domain == domain)
+        })
+        .collect();
+    if hosts.is_empty() {
+        match domain {
+            Some(domain) => println!("No host with domain {}", domain),
+            None => println!("No hosts configured"),
+        }
+        return 1;
+    }
+    match command {
+        Some("status") => status(&hosts),
+        Some("renew") => renew(config, &hosts, force),
+        Some("revoke") => revoke(config, &hosts, reason),
+        _ => {
+            println!("{}", CERT_USAGE);
+            1
+        }
+    }
+}
+
+fn parse_reason(name: &str) -> Option<Reason> {
+    match name {
+        "unspecified" => Some(Reason::Unspecified),
+        "key-compromise" => Some(Reason::KeyCompromise),
+        "superseded" => Some(Reason::Superseded),
+        "cessation-of-operation" => Some(Reason::CessationOfOperation),
+        _ => None,
+    }
+}
+
+fn status(hosts: &[Host]) -> i32 {
+    let mut code = 0;
+    for host in hosts.iter() {
+        match time_to_expiration(&host.pem_name) {
+            Some(remaining) => {
+                let days = remaining.as_secs() / ADAY.as_secs();
+                let due = if remaining < AMONTH {
+                    ", renewal due"
+                } else {
+                    ""
+                };
+                println!(
+                    "{}: {} expires in {} days{}",
+                    host.domain, host.pem_name, days, due
+                );
+            }
+            None => {
+                println!("{}: {} is missing or expired", host.domain, host.pem_name);
+                code = 1;
+            }
+        }
+    }
+    code
+}
+
+fn renew(config: &Config, hosts: &[Host], force: bool) -> i32 {
+    if config.tls.mode != TlsMode::Acme {
+        println!("Certificates are renewed only with tls.mode acme");
+        return 1;
+    }
+    let storage = Storage::new(&config.storage);
+    let challenges = ChallengeStore::new(&storage);
+    let mut code = 0;
+    for host in hosts.iter() {
+        match acme::request_cert(config, &challenges, host, force) {
+            Ok(_) => println!("{}: certificate {} ok", host.domain, host.pem_name),
+            Err(err) => {
+                println!("{}: renewal failed: {}", host.domain, err);
+                code = 1;
+            }
+        }
+    }
+    code
+}
+
+fn revoke(config: &Config, hosts: &[Host], reason: Reason) -> i32 {
+    if config.tls.mode != TlsMode::Acme {
+        println!("Certificates are revoked only with tls.mode acme");
+        return 1;
+    }
+    let mut code = 0;
+    for host in hosts.iter() {
+        match acme::revoke_cert(config, host, reason.into()) {
+            Ok(_) => println!(
+                "{}: certificate {} revoked, replace it with cert renew --force",
+                host.domain, host.pem_name
+            ),
+            Err(err) => {
+                println!("{}: revocation failed: {}", host.domain, err);
+                code = 1;
+            }
+        }
+    }
+    code
+}
@@ -0,0 +1,1480 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ *  Copyright (C) 2020  Mles developers
+ */
+use regex::Regex;
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+use tokio_rustls::rustls::ProtocolVersion;
+use tokio_rustls::webpki;
+
+use crate::acme::Eab;
+use crate::tls;
+
+pub const SRV_ADDR: &str = "35.157.221.129:8077"; // mles.io
+const LETSENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+const LETSENCRYPT_STAGING: &str = "https://acme-staging-v02.api.letsencrypt.org/directory";
+const ZEROSSL: &str = "https://acme.zerossl.com/v2/DV90";
+const KEEPALIVE: u64 = 5;
+const PING_INTERVAL: u64 = 12;
+const QUEUE_CAPACITY: usize = 256;
+const RATE_LIMIT_INTERVAL: u64 = 60;
+const RELOAD_INTERVAL: u64 = 60;
+const DNS_TTL: u32 = 60;
+const PROPAGATION_DELAY: u64 = 30;
+const TSIG_ALGORITHMS: &[&str] = &["hmac-sha1", "hmac-sha256", "hmac-sha512"];
+const TLS_VERSIONS: &[&str] = &["1.2", "1.3"];
+
+/* Environment variables which override the values of the configuration file */
+const ENV_WWW_ROOT: &str = "MLES_WEBPROXY_WWW_ROOT";
+const ENV_EMAIL: &str = "MLES_WEBPROXY_EMAIL";
+const ENV_DOMAIN: &str = "MLES_WEBPROXY_DOMAIN";
+const ENV_SRV_ADDR: &str = "MLES_WEBPROXY_SRV_ADDR";
+const ENV_KEY: &str = "MLES_KEY";
+const ENV_ADDR_KEY: &str = "MLES_ADDR_KEY";
+const ENV_EAB_KID: &str = "MLES_WEBPROXY_EAB_KID";
+const ENV_EAB_HMAC_KEY: &str = "MLES_WEBPROXY_EAB_HMAC_KEY";
+const ENV_TSIG_SECRET: &str = "MLES_WEBPROXY_TSIG_SECRET";
+const ENV_STATE_DIR: &str = "MLES_WEBPROXY_STATE_DIR";
+const ENV_PASSPHRASE: &str = "MLES_WEBPROXY_PASSPHRASE";
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub www_root: String,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    #[serde(default)]
+    pub acme: AcmeConfig,
+    #[serde(default, rename = "listener")]
+    pub listeners: Vec<ListenerConfig>,
+    #[serde(default)]
+    pub upstream: UpstreamConfig,
+    #[serde(default)]
+    pub keepalive: KeepaliveConfig,
+    #[serde(default)]
+    pub queue: QueueConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default, rename = "vhost")]
+    pub vhosts: Vec<VhostConfig>,
+    #[serde(default)]
+    pub storage: StorageConfig,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    /// Certificates are requested from an ACME provider
+    Acme,
+    /// Existing certificate and key files are used and reloaded on change
+    Files,
+    /// No TLS, the proxy runs behind a TLS terminating reverse proxy
+    Off,
+    /// Certificates are generated locally for development and testing
+    Dev,
+}
+
+impl Default for TlsMode {
+    fn default() -> Self {
+        TlsMode::Acme
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct TlsConfig {
+    pub mode: TlsMode,
+    pub email: String,
+    pub domain: String,
+    /// Additional names of the certificate, wildcards like "*.mles.io" require dns-01
+    pub alt_names: Vec<String>,
+    /// Certificate chain in PEM format with mode files
+    pub certificate: String,
+    /// Private key in PEM format with mode files
+    pub private_key: String,
+    /// How often certificate files are checked for changes in seconds
+    pub reload_interval: u64,
+    /// Sign the certificates of mode dev with a local CA instead of self-signing them
+    pub local_ca: bool,
+    /// Minimum TLS version, "1.2" or "1.3"
+    pub min_version: String,
+    /// Cipher suites in order of preference, e.g. "TLS13_AES_256_GCM_SHA384",
+    /// all suites supported by rustls if empty
+    pub cipher_suites: Vec<String>,
+    /// ALPN protocols offered to clients in order of preference
+    pub alpn_protocols: Vec<String>,
+    /// Staple OCSP responses of the certificates to the handshakes
+    pub ocsp_stapling: bool,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            mode: TlsMode::default(),
+            email: "".to_string(),
+            domain: "".to_string(),
+            alt_names: Vec::new(),
+            certificate: "".to_string(),
+            private_key: "".to_string(),
+            reload_interval: RELOAD_INTERVAL,
+            local_ca: false,
+            min_version: "1.2".to_string(),
+            cipher_suites: Vec::new(),
+            alpn_protocols: vec!["http/1.1".to_string()],
+            ocsp_stapling: true,
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn reload_duration(&self) -> Duration {
+        Duration::from_secs(self.reload_interval)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct AcmeConfig {
+    /// "letsencrypt", "letsencrypt-staging", "zerossl" or the URL of an ACME directory
+    pub directory: String,
+    /// External Account Binding key identifier
+    pub eab_kid: String,
+    /// External Account Binding HMAC key in base64url
+    pub eab_hmac_key: String,
+    /// Challenge type used to prove the ownership of the domain
+    pub challenge: AcmeChallenge,
+    /// DNS-01 challenge settings
+    pub dns: DnsConfig,
+}
+
+impl Default for AcmeConfig {
+    fn default() -> Self {
+        AcmeConfig {
+            directory: "letsencrypt".to_string(),
+            eab_kid: "".to_string(),
+            eab_hmac_key: "".to_string(),
+            challenge: AcmeChallenge::default(),
+            dns: DnsConfig::default(),
+        }
+    }
+}
+
+impl AcmeConfig {
+    pub fn directory_url(&self) -> &str {
+        match self.directory.as_str() {
+            "letsencrypt" => LETSENCRYPT,
+            "letsencrypt-staging" => LETSENCRYPT_STAGING,
+            "zerossl" => ZEROSSL,
+            url => url,
+        }
+    }
+
+    pub fn eab(&self) -> Option<Eab> {
+        if self.eab_kid.is_empty() {
+            return None;
+        }
+        let hmac_key = base64::decode_config(&self.eab_hmac_key, base64::URL_SAFE_NO_PAD)
+            .or_else(|_| base64::decode_config(&self.eab_hmac_key, base64::URL_SAFE))
+            .ok()?;
+        Some(Eab {
+            kid: self.eab_kid.clone(),
+            hmac_key,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum AcmeChallenge {
+    /// Proof served over plain HTTP by the acme listeners on port 80
+    #[serde(rename = "http-01")]
+    Http01,
+    /// Proof served within the TLS handshake by the tls listeners on port 443
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+    /// Proof published as a TXT record, also for wildcard names
+    #[serde(rename = "dns-01")]
+    Dns01,
+}
+
+impl Default for AcmeChallenge {
+    fn default() -> Self {
+        AcmeChallenge::Http01
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DnsProvider {
+    /// Runs the hook with "set" or "unset", the record name and the value
+    Exec,
+    /// Sends a dynamic update to the primary server of the zone
+    Rfc2136,
+}
+
+impl Default for DnsProvider {
+    fn default() -> Self {
+        DnsProvider::Exec
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct DnsConfig {
+    pub provider: DnsProvider,
+    /// Executable of the exec provider
+    pub hook: String,
+    /// Primary DNS server address of the rfc2136 provider
+    pub server: String,
+    /// Zone which contains the _acme-challenge records
+    pub zone: String,
+    /// TSIG key name, updates are unsigned without it
+    pub tsig_key: String,
+    /// "hmac-sha1", "hmac-sha256" or "hmac-sha512"
+    pub tsig_algorithm: String,
+    /// TSIG secret in base64
+    pub tsig_secret: String,
+    /// TTL of the TXT records in seconds
+    pub ttl: u32,
+    /// How long to wait for the records to reach all name servers in seconds
+    pub propagation_delay: u64,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        DnsConfig {
+            provider: DnsProvider::default(),
+            hook: "".to_string(),
+            server: "".to_string(),
+            zone: "".to_string(),
+            tsig_key: "".to_string(),
+            tsig_algorithm: "hmac-sha256".to_string(),
+            tsig_secret: "".to_string(),
+            ttl: DNS_TTL,
+            propagation_delay: PROPAGATION_DELAY,
+        }
+    }
+}
+
+impl DnsConfig {
+    pub fn propagation_duration(&self) -> Duration {
+        Duration::from_secs(self.propagation_delay)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerRole {
+    /// Redirects plain HTTP to the TLS service
+    Redirect,
+    /// Serves ACME HTTP-01 challenges and redirects everything else
+    Acme,
+    /// Serves WebSocket proxy and static files over TLS
+    Tls,
+    /// Serves WebSocket proxy and static files over plain HTTP
+    Http,
+}
+
+impl fmt::Display for ListenerRole {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenerRole::Redirect => write!(f, "redirect"),
+            ListenerRole::Acme => write!(f, "acme"),
+            ListenerRole::Tls => write!(f, "tls"),
+            ListenerRole::Http => write!(f, "http"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientAddrSource {
+    /// Address of the TCP peer
+    Peer,
+    /// Forwarded or X-Forwarded-For headers set by a trusted proxy
+    Forwarded,
+    /// PROXY protocol v1 or v2 header preceding the connection
+    ProxyProtocol,
+}
+
+impl Default for ClientAddrSource {
+    fn default() -> Self {
+        ClientAddrSource::Peer
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: String,
+    pub port: u16,
+    pub role: ListenerRole,
+    /// Public port of the TLS service used in redirects, e.g. behind NAT
+    #[serde(default)]
+    pub redirect_port: Option<u16>,
+    /// Where the real client address is taken from
+    #[serde(default)]
+    pub client_addr: ClientAddrSource,
+    /// Peers whose forwarding headers are trusted
+    #[serde(default = "default_trusted_proxies")]
+    pub trusted_proxies: Vec<String>,
+}
+
+impl ListenerConfig {
+    fn new(address: &str, port: u16, role: ListenerRole) -> Self {
+        ListenerConfig {
+            address: address.to_string(),
+            port,
+            role,
+            redirect_port: None,
+            client_addr: ClientAddrSource::Peer,
+            trusted_proxies: default_trusted_proxies(),
+        }
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        let ip = self.address.parse::<IpAddr>().unwrap(); //already checked
+        SocketAddr::new(ip, self.port)
+    }
+
+    /// Returns the redirect target of this listener for the given domain and path.
+    pub fn redirect_uri(&self, domain: &str, path: &str) -> String {
+        match self.redirect_port {
+            Some(port) if port != 443 => format!("https://{}:{}/{}", domain, port, path),
+            _ => format!("https://{}/{}", domain, path),
+        }
+    }
+}
+
+fn default_trusted_proxies() -> Vec<String> {
+    vec!["127.0.0.1".to_string(), "::1".to_string()]
+}
+
+fn default_listeners(mode: TlsMode, challenge: AcmeChallenge) -> Vec<ListenerConfig> {
+    match mode {
+        TlsMode::Acme if challenge == AcmeChallenge::Http01 => vec![
+            ListenerConfig::new("::", 80, ListenerRole::Acme),
+            ListenerConfig::new("::", 443, ListenerRole::Tls),
+        ],
+        TlsMode::Acme | TlsMode::Files => vec![
+            ListenerConfig::new("::", 80, ListenerRole::Redirect),
+            ListenerConfig::new("::", 443, ListenerRole::Tls),
+        ],
+        TlsMode::Off => vec![ListenerConfig::new("127.0.0.1", 8080, ListenerRole::Http)],
+        TlsMode::Dev => vec![ListenerConfig::new("127.0.0.1", 8443, ListenerRole::Tls)],
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct UpstreamConfig {
+    pub address: String,
+    /// Further Mles servers in failover order
+    pub fallback_addresses: Vec<String>,
+    pub key: String,
+    pub addr_key: String,
+    /// Protocol spoken with the Mles server
+    pub dialect: UpstreamDialect,
+    /// TLS server name of a Mles v2 server, plain WebSocket if empty
+    pub server_name: String,
+    /// Further groups of Mles servers which channels are spread over
+    #[serde(rename = "shard")]
+    pub shards: Vec<ShardConfig>,
+    /// Channels placed on a given shard, the others are placed by hash
+    #[serde(rename = "route")]
+    pub routes: Vec<RouteConfig>,
+    /// Connections shared between the clients of a channel
+    pub fanout: Fanout,
+}
+
+impl UpstreamConfig {
+    /// Returns the Mles servers in failover order.
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        parse_addresses(&self.address, &self.fallback_addresses)
+    }
+}
+
+/// Name of the shard of the address and fallback_addresses of an upstream
+pub const DEFAULT_SHARD: &str = "default";
+
+/// Mles servers of a shard, which use the key and dialect of the upstream.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShardConfig {
+    pub name: String,
+    pub address: String,
+    #[serde(default)]
+    pub fallback_addresses: Vec<String>,
+}
+
+impl ShardConfig {
+    /// Returns the Mles servers in failover order.
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        parse_addresses(&self.address, &self.fallback_addresses)
+    }
+}
+
+/// Rule placing channels on a shard, by the exact name, a prefix or a
+/// regular expression. Exact names apply before prefixes and prefixes
+/// before regexes, otherwise the first matching rule applies.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    #[serde(default)]
+    pub channel: String,
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default)]
+    pub regex: String,
+    pub shard: String,
+}
+
+fn parse_addresses(address: &str, fallback_addresses: &[String]) -> Vec<SocketAddr> {
+    std::iter::once(address)
+        .chain(fallback_addresses.iter().map(|address| address.as_str()))
+        .map(|address| address.parse::<SocketAddr>().unwrap()) //already checked
+        .collect()
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        UpstreamConfig {
+            address: SRV_ADDR.to_string(),
+            fallback_addresses: Vec::new(),
+            key: "".to_string(),
+            addr_key: "".to_string(),
+            dialect: UpstreamDialect::default(),
+            server_name: "".to_string(),
+            shards: Vec::new(),
+            routes: Vec::new(),
+            fanout: Fanout::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamDialect {
+    /// Mles v1 TCP protocol, each message preceded by its MsgHdr
+    Legacy,
+    /// Mles v2 WebSocket protocol, the channel is joined with a JSON frame
+    V2,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Fanout {
+    /// Each client has its own connection for each channel
+    Off,
+    /// The clients of a channel share one connection
+    Channel,
+    /// The clients of a channel with the same uid share one connection
+    Uid,
+}
+
+impl Default for Fanout {
+    fn default() -> Self {
+        Fanout::Off
+    }
+}
+
+impl Default for UpstreamDialect {
+    fn default() -> Self {
+        UpstreamDialect::Legacy
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct StorageConfig {
+    /// State directory of ACME accounts, certificates and private keys
+    pub dir: String,
+    /// Encrypts the private keys at rest if given
+    pub passphrase: String,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            dir: ".".to_string(),
+            passphrase: "".to_string(),
+        }
+    }
+}
+
+/// A further host name served with its own certificate, static files and
+/// upstream, selected by SNI and the Host header.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VhostConfig {
+    pub domain: String,
+    #[serde(default)]
+    pub alt_names: Vec<String>,
+    /// Certificate chain in PEM format with mode files
+    #[serde(default)]
+    pub certificate: String,
+    /// Private key in PEM format with mode files
+    #[serde(default)]
+    pub private_key: String,
+    /// Defaults to the top level www_root
+    #[serde(default)]
+    pub www_root: Option<String>,
+    /// Defaults to the top level upstream
+    #[serde(default)]
+    pub upstream: Option<UpstreamConfig>,
+}
+
+/// A host as served, the first one is the default for unknown names.
+#[derive(Clone, Debug)]
+pub struct Host {
+    pub domain: String,
+    pub alt_names: Vec<String>,
+    pub pem_name: String,
+    pub key_name: String,
+    pub www_root: String,
+    pub upstream: UpstreamConfig,
+}
+
+impl Host {
+    /// Returns true if the name is the domain or one of the alternative names.
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_lowercase();
+        std::iter::once(&self.domain)
+            .chain(self.alt_names.iter())
+            .any(|pattern| {
+                let pattern = pattern.to_lowercase();
+                if pattern.starts_with("*.") {
+                    /* A wildcard matches a single label */
+                    match name.find('.') {
+                        Some(pos) => pos > 0 && name[pos..] == pattern[1..],
+                        None => false,
+                    }
+                } else {
+                    name == pattern
+                }
+            })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct KeepaliveConfig {
+    /// TCP keepalive of the upstream Mles connection in seconds
+    pub tcp: u64,
+    /// WebSocket ping interval in seconds
+    pub ping_interval: u64,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        KeepaliveConfig {
+            tcp: KEEPALIVE,
+            ping_interval: PING_INTERVAL,
+        }
+    }
+}
+
+impl KeepaliveConfig {
+    pub fn tcp_duration(&self) -> Duration {
+        Duration::from_secs(self.tcp)
+    }
+
+    pub fn ping_duration(&self) -> Duration {
+        Duration::from_secs(self.ping_interval)
+    }
+}
+
+/// Queues of a WebSocket session, one direction towards the Mles server and
+/// one towards the client.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct QueueConfig {
+    /// Messages kept in each queue
+    pub capacity: usize,
+    /// Policy when a queue towards the Mles server is full
+    pub upstream: OverflowPolicy,
+    /// Policy when a queue towards the client is full
+    pub downstream: OverflowPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            capacity: QUEUE_CAPACITY,
+            upstream: OverflowPolicy::Block,
+            downstream: OverflowPolicy::Block,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// The side filling the queue waits until there is room
+    Block,
+    /// The oldest queued message is dropped for the new one
+    DropOldest,
+    /// The client is closed as overloaded
+    Close,
+}
+
+/// WebSocket connections accepted from each client address, as taken by
+/// the listener.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct RateLimitConfig {
+    /// Connections per interval, unlimited if zero
+    pub connections: u32,
+    /// Interval in seconds
+    pub interval: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            connections: 0,
+            interval: RATE_LIMIT_INTERVAL,
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn interval_duration(&self) -> Duration {
+        Duration::from_secs(self.interval)
+    }
+}
+
+#[derive(Debug)]
+pub struct FieldError {
+    pub field: String,
+    pub reason: String,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, std::io::Error),
+    Parse(String, toml::de::Error),
+    Invalid(Vec<FieldError>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "cannot read {}: {}", path, err),
+            ConfigError::Parse(path, err) => write!(f, "cannot parse {}: {}", path, err),
+            ConfigError::Invalid(errors) => {
+                write!(f, "invalid configuration:")?;
+                for err in errors {
+                    write!(f, "\n  {}: {}", err.field, err.reason)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Reads the configuration file, applies environment overrides and validates the result.
+    ///
+    /// With `dev` the TLS mode is forced to dev, so a production configuration
+    /// can be run locally without reaching the ACME provider.
+    pub fn load<P: AsRef<Path>>(path: P, dev: bool) -> Result<Config, ConfigError> {
+        let name = path.as_ref().display().to_string();
+        let content =
+            std::fs::read_to_string(&path).map_err(|err| ConfigError::Io(name.clone(), err))?;
+        Config::parse(name, &content, dev)
+    }
+
+    fn parse(name: String, content: &str, dev: bool) -> Result<Config, ConfigError> {
+        let mut config: Config =
+            toml::from_str(content).map_err(|err| ConfigError::Parse(name, err))?;
+        config.apply_env();
+        if dev {
+            config.use_dev_mode();
+        }
+        if config.tls.mode == TlsMode::Dev && config.tls.domain.is_empty() {
+            config.tls.domain = "localhost".to_string();
+        }
+        if config.listeners.is_empty() {
+            config.listeners = default_listeners(config.tls.mode, config.acme.challenge);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) {
+        override_from_env(ENV_WWW_ROOT, &mut self.www_root);
+        override_from_env(ENV_EMAIL, &mut self.tls.email);
+        override_from_env(ENV_DOMAIN, &mut self.tls.domain);
+        override_from_env(ENV_SRV_ADDR, &mut self.upstream.address);
+        override_from_env(ENV_KEY, &mut self.upstream.key);
+        override_from_env(ENV_ADDR_KEY, &mut self.upstream.addr_key);
+        override_from_env(ENV_EAB_KID, &mut self.acme.eab_kid);
+        override_from_env(ENV_EAB_HMAC_KEY, &mut self.acme.eab_hmac_key);
+        override_from_env(ENV_TSIG_SECRET, &mut self.acme.dns.tsig_secret);
+        override_from_env(ENV_STATE_DIR, &mut self.storage.dir);
+        override_from_env(ENV_PASSPHRASE, &mut self.storage.passphrase);
+    }
+
+    fn use_dev_mode(&mut self) {
+        self.tls.mode = TlsMode::Dev;
+        /* There are no challenges to answer, only the redirects remain */
+        for listener in self.listeners.iter_mut() {
+            if listener.role == ListenerRole::Acme {
+                listener.role = ListenerRole::Redirect;
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+
+        if self.www_root.is_empty() {
+            errors.push(field_error("www_root", "must not be empty"));
+        } else if !Path::new(&self.www_root).is_dir() {
+            errors.push(field_error(
+                "www_root",
+                &format!("{} is not a directory", self.www_root),
+            ));
+        }
+        if self.tls.mode == TlsMode::Acme {
+            if self.tls.email.is_empty() {
+                errors.push(field_error("tls.email", "must not be empty"));
+            } else if !self.tls.email.contains('@') {
+                errors.push(field_error(
+                    "tls.email",
+                    &format!("{} is not an email address", self.tls.email),
+                ));
+            }
+        }
+        if self.tls.mode == TlsMode::Acme {
+            let url = self.acme.directory_url();
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                errors.push(field_error(
+                    "acme.directory",
+                    &format!("{} is not a known CA or an URL", self.acme.directory),
+                ));
+            }
+            if self.acme.eab_kid.is_empty() != self.acme.eab_hmac_key.is_empty() {
+                errors.push(field_error(
+                    "acme.eab_kid",
+                    "eab_kid and eab_hmac_key must be given together",
+                ));
+            } else if !self.acme.eab_kid.is_empty() && self.acme.eab().is_none() {
+                errors.push(field_error("acme.eab_hmac_key", "is not valid base64url"));
+            }
+            if self.acme.directory == "zerossl" && self.acme.eab_kid.is_empty() {
+                errors.push(field_error(
+                    "acme.eab_kid",
+                    "zerossl requires external account binding",
+                ));
+            }
+        }
+        if self.tls.mode == TlsMode::Acme && self.acme.challenge == AcmeChallenge::Dns01 {
+            self.validate_dns(&mut errors);
+        }
+        if self.tls.mode != TlsMode::Off && self.tls.domain.is_empty() {
+            errors.push(field_error("tls.domain", "must not be empty"));
+        }
+        self.validate_names("tls", &self.tls.domain, &self.tls.alt_names, &mut errors);
+        if self.tls.mode == TlsMode::Files {
+            validate_cert_files(
+                "tls",
+                &self.tls.certificate,
+                &self.tls.private_key,
+                &mut errors,
+            );
+            if self.tls.reload_interval == 0 {
+                errors.push(field_error("tls.reload_interval", "must not be zero"));
+            }
+        }
+        if self.tls.mode != TlsMode::Off {
+            self.validate_policy(&mut errors);
+        }
+        for (i, vhost) in self.vhosts.iter().enumerate() {
+            let prefix = format!("vhost[{}]", i);
+            if vhost.domain.is_empty() {
+                errors.push(field_error(
+                    &format!("{}.domain", prefix),
+                    "must not be empty",
+                ));
+            }
+            self.validate_names(&prefix, &vhost.domain, &vhost.alt_names, &mut errors);
+            if self.tls.mode == TlsMode::Files {
+                validate_cert_files(&prefix, &vhost.certificate, &vhost.private_key, &mut errors);
+            }
+            if let Some(www_root) = &vhost.www_root {
+                if !Path::new(www_root).is_dir() {
+                    errors.push(field_error(
+                        &format!("{}.www_root", prefix),
+                        &format!("{} is not a directory", www_root),
+                    ));
+                }
+            }
+            if let Some(upstream) = &vhost.upstream {
+                validate_upstream(&format!("{}.upstream", prefix), upstream, &mut errors);
+            }
+        }
+        let hosts = self.hosts();
+        for (i, host) in hosts.iter().enumerate() {
+            let duplicate = hosts
+                .iter()
+                .take(i)
+                .any(|other| other.domain == host.domain || other.matches(&host.domain));
+            if duplicate && !host.domain.is_empty() {
+                errors.push(field_error(
+                    "vhost",
+                    &format!("{} is served by more than one host", host.domain),
+                ));
+            }
+        }
+        for (i, listener) in self.listeners.iter().enumerate() {
+            if listener.address.parse::<IpAddr>().is_err() {
+                errors.push(field_error(
+                    &format!("listener[{}].address", i),
+                    &format!("{} is not an IP address", listener.address),
+                ));
+            }
+            if listener.port == 0 {
+                errors.push(field_error(
+                    &format!("listener[{}].port", i),
+                    "must not be zero",
+                ));
+            }
+            let redirects =
+                listener.role == ListenerRole::Redirect || listener.role == ListenerRole::Acme;
+            if listener.redirect_port.is_some() && !redirects {
+                errors.push(field_error(
+                    &format!("listener[{}].redirect_port", i),
+                    &format!("is not used with role {}", listener.role),
+                ));
+            }
+            let plain_only = listener.client_addr != ClientAddrSource::Peer;
+            if plain_only && listener.role != ListenerRole::Http {
+                errors.push(field_error(
+                    &format!("listener[{}].client_addr", i),
+                    &format!("is only supported with role http, not {}", listener.role),
+                ));
+            }
+            for proxy in listener.trusted_proxies.iter() {
+                if proxy.parse::<IpAddr>().is_err() {
+                    errors.push(field_error(
+                        &format!("listener[{}].trusted_proxies", i),
+                        &format!("{} is not an IP address", proxy),
+                    ));
+                }
+            }
+            if self.tls.mode == TlsMode::Off && listener.role != ListenerRole::Http {
+                errors.push(field_error(
+                    &format!("listener[{}].role", i),
+                    &format!("{} requires TLS, but tls.mode is off", listener.role),
+                ));
+            }
+            if self.tls.mode != TlsMode::Acme && listener.role == ListenerRole::Acme {
+                errors.push(field_error(
+                    &format!("listener[{}].role", i),
+                    "acme requires tls.mode acme",
+                ));
+            }
+            if listener.redirect_port == Some(0) {
+                errors.push(field_error(
+                    &format!("listener[{}].redirect_port", i),
+                    "must not be zero",
+                ));
+            }
+            for (j, other) in self.listeners.iter().enumerate().take(i) {
+                if other.address != listener.address || other.port != listener.port {
+                    continue;
+                }
+                errors.push(field_error(
+                    &format!("listener[{}]", i),
+                    &format!(
+                        "{}:{} already used by listener[{}]",
+                        listener.address, listener.port, j
+                    ),
+                ));
+            }
+        }
+        match self.tls.mode {
+            TlsMode::Acme => {
+                if !self.has_listener(ListenerRole::Tls) {
+                    errors.push(field_error("listener", "no listener with role tls"));
+                }
+                let http01 = self.acme.challenge == AcmeChallenge::Http01;
+                if http01 && !self.has_listener(ListenerRole::Acme) {
+                    errors.push(field_error("listener", "no listener with role acme"));
+                }
+            }
+            TlsMode::Files | TlsMode::Dev => {
+                if !self.has_listener(ListenerRole::Tls) {
+                    errors.push(field_error("listener", "no listener with role tls"));
+                }
+            }
+            TlsMode::Off => {
+                if !self.has_listener(ListenerRole::Http) {
+                    errors.push(field_error("listener", "no listener with role http"));
+                }
+            }
+        }
+        if self.storage.dir.is_empty() {
+            errors.push(field_error("storage.dir", "must not be empty"));
+        } else {
+            let dir = Path::new(&self.storage.dir);
+            if dir.exists() && !dir.is_dir() {
+                errors.push(field_error(
+                    "storage.dir",
+                    &format!("{} is not a directory", self.storage.dir),
+                ));
+            }
+        }
+        validate_upstream("upstream", &self.upstream, &mut errors);
+        if self.keepalive.tcp == 0 {
+            errors.push(field_error("keepalive.tcp", "must not be zero"));
+        }
+        if self.keepalive.ping_interval == 0 {
+            errors.push(field_error("keepalive.ping_interval", "must not be zero"));
+        }
+        if self.queue.capacity == 0 {
+            errors.push(field_error("queue.capacity", "must not be zero"));
+        }
+        if self.rate_limit.connections > 0 && self.rate_limit.interval == 0 {
+            errors.push(field_error("rate_limit.interval", "must not be zero"));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(errors))
+        }
+    }
+
+    fn validate_names(
+        &self,
+        prefix: &str,
+        domain: &str,
+        alt_names: &[String],
+        errors: &mut Vec<FieldError>,
+    ) {
+        if domain.starts_with("*.") {
+            errors.push(field_error(
+                &format!("{}.domain", prefix),
+                &format!("must not be a wildcard, use {}.alt_names", prefix),
+            ));
+        }
+        for name in alt_names.iter() {
+            if name.is_empty() {
+                errors.push(field_error(
+                    &format!("{}.alt_names", prefix),
+                    "must not contain empty names",
+                ));
+            } else if name.starts_with("*.")
+                && self.tls.mode == TlsMode::Acme
+                && self.acme.challenge != AcmeChallenge::Dns01
+            {
+                errors.push(field_error(
+                    &format!("{}.alt_names", prefix),
+                    &format!("{} is a wildcard which requires challenge dns-01", name),
+                ));
+            }
+        }
+    }
+
+    fn validate_policy(&self, errors: &mut Vec<FieldError>) {
+        if !TLS_VERSIONS.contains(&self.tls.min_version.as_str()) {
+            errors.push(field_error(
+                "tls.min_version",
+                &format!(
+                    "{} is not one of {}",
+                    self.tls.min_version,
+                    TLS_VERSIONS.join(", ")
+                ),
+            ));
+        }
+        for name in self.tls.cipher_suites.iter() {
+            if tls::cipher_suite(name).is_none() {
+                errors.push(field_error(
+                    "tls.cipher_suites",
+                    &format!("{} is not supported", name),
+                ));
+            }
+        }
+        let tls13 = self.tls.cipher_suites.iter().any(|name| {
+            tls::cipher_suite(name).map_or(false, |suite| {
+                suite.usable_for_version(ProtocolVersion::TLSv1_3)
+            })
+        });
+        if !self.tls.cipher_suites.is_empty() && self.tls.min_version == "1.3" && !tls13 {
+            errors.push(field_error(
+                "tls.cipher_suites",
+                "no TLS 1.3 cipher suite with min_version 1.3",
+            ));
+        }
+        for protocol in self.tls.alpn_protocols.iter() {
+            if protocol.is_empty() || protocol.len() > 255 {
+                errors.push(field_error(
+                    "tls.alpn_protocols",
+                    &format!("{:?} is not a valid protocol name", protocol),
+                ));
+            } else if protocol.as_bytes() == tls::ACME_TLS_ALPN {
+                errors.push(field_error(
+                    "tls.alpn_protocols",
+                    &format!("{} is reserved for ACME challenges", protocol),
+                ));
+            }
+        }
+    }
+
+    fn validate_dns(&self, errors: &mut Vec<FieldError>) {
+        let dns = &self.acme.dns;
+        match dns.provider {
+            DnsProvider::Exec => {
+                if dns.hook.is_empty() {
+                    errors.push(field_error("acme.dns.hook", "must not be empty"));
+                } else if !Path::new(&dns.hook).is_file() {
+                    errors.push(field_error(
+                        "acme.dns.hook",
+                        &format!("{} is not a file", dns.hook),
+                    ));
+                }
+            }
+            DnsProvider::Rfc2136 => {
+                if dns.server.parse::<SocketAddr>().is_err() {
+                    errors.push(field_error(
+                        "acme.dns.server",
+                        &format!("{} is not of form x.x.x.x:p", dns.server),
+                    ));
+                }
+                if dns.zone.is_empty() {
+                    errors.push(field_error("acme.dns.zone", "must not be empty"));
+                }
+                if dns.tsig_key.is_empty() != dns.tsig_secret.is_empty() {
+                    errors.push(field_error(
+                        "acme.dns.tsig_key",
+                        "tsig_key and tsig_secret must be given together",
+                    ));
+                } else if base64::decode(&dns.tsig_secret).is_err() {
+                    errors.push(field_error("acme.dns.tsig_secret", "is not valid base64"));
+                }
+                if !TSIG_ALGORITHMS.contains(&dns.tsig_algorithm.as_str()) {
+                    errors.push(field_error(
+                        "acme.dns.tsig_algorithm",
+                        &format!("{} is not supported", dns.tsig_algorithm),
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Returns the certificate and private key files of a host, the requested
+    /// ones are kept in the state directory.
+    fn cert_files(&self, domain: &str, certificate: &str, private_key: &str) -> (String, String) {
+        match self.tls.mode {
+            TlsMode::Files => (certificate.to_string(), private_key.to_string()),
+            mode => {
+                /* Generated certificates must not be mistaken for requested ones */
+                let suffix = if mode == TlsMode::Dev { ".dev" } else { "" };
+                let dir = Path::new(&self.storage.dir);
+                (
+                    dir.join(format!("{}{}.pem", domain, suffix))
+                        .display()
+                        .to_string(),
+                    dir.join(format!("{}{}.key", domain, suffix))
+                        .display()
+                        .to_string(),
+                )
+            }
+        }
+    }
+
+    /// Returns the default host followed by the virtual hosts.
+    pub fn hosts(&self) -> Vec<Host> {
+        let (pem_name, key_name) = self.cert_files(
+            &self.tls.domain,
+            &self.tls.certificate,
+            &self.tls.private_key,
+        );
+        let mut hosts = vec![Host {
+            domain: self.tls.domain.clone(),
+            alt_names: self.tls.alt_names.clone(),
+            pem_name,
+            key_name,
+            www_root: self.www_root.clone(),
+            upstream: self.upstream.clone(),
+        }];
+        for vhost in self.vhosts.iter() {
+            let (pem_name, key_name) =
+                self.cert_files(&vhost.domain, &vhost.certificate, &vhost.private_key);
+            hosts.push(Host {
+                domain: vhost.domain.clone(),
+                alt_names: vhost.alt_names.clone(),
+                pem_name,
+                key_name,
+                www_root: vhost
+                    .www_root
+                    .clone()
+                    .unwrap_or_else(|| self.www_root.clone()),
+                upstream: vhost
+                    .upstream
+                    .clone()
+                    .unwrap_or_else(|| self.upstream.clone()),
+            });
+        }
+        hosts
+    }
+
+    pub fn has_listener(&self, role: ListenerRole) -> bool {
+        self.listeners.iter().any(|listener| listener.role == role)
+    }
+
+    pub fn listeners(&self, role: ListenerRole) -> impl Iterator<Item = &ListenerConfig> {
+        self.listeners
+            .iter()
+            .filter(move |listener| listener.role == role)
+    }
+}
+
+fn override_from_env(name: &str, value: &mut String) {
+    if let Ok(val) = env::var(name) {
+        *value = val;
+    }
+}
+
+fn validate_upstream(prefix: &str, upstream: &UpstreamConfig, errors: &mut Vec<FieldError>) {
+    validate_addresses(
+        prefix,
+        &upstream.address,
+        &upstream.fallback_addresses,
+        errors,
+    );
+    let shard_names: Vec<&String> = upstream.shards.iter().map(|shard| &shard.name).collect();
+    for (i, shard) in upstream.shards.iter().enumerate() {
+        let shard_prefix = format!("{}.shard[{}]", prefix, i);
+        if shard.name.is_empty() {
+            errors.push(field_error(
+                &format!("{}.name", shard_prefix),
+                "must not be empty",
+            ));
+        } else if shard.name == DEFAULT_SHARD || shard_names[..i].contains(&&shard.name) {
+            errors.push(field_error(
+                &format!("{}.name", shard_prefix),
+                &format!("{} is already used", shard.name),
+            ));
+        }
+        validate_addresses(
+            &shard_prefix,
+            &shard.address,
+            &shard.fallback_addresses,
+            errors,
+        );
+    }
+    for (i, route) in upstream.routes.iter().enumerate() {
+        let route_prefix = format!("{}.route[{}]", prefix, i);
+        let matchers = [&route.channel, &route.prefix, &route.regex];
+        let given = matchers.iter().filter(|matcher| !matcher.is_empty());
+        if given.count() != 1 {
+            errors.push(field_error(
+                &route_prefix,
+                "needs one of channel, prefix or regex",
+            ));
+        }
+        if let Err(err) = Regex::new(&route.regex) {
+            errors.push(field_error(
+                &format!("{}.regex", route_prefix),
+                &err.to_string(),
+            ));
+        }
+        if route.shard != DEFAULT_SHARD && !shard_names.contains(&&route.shard) {
+            errors.push(field_error(
+                &format!("{}.shard", route_prefix),
+                &format!("no shard named {}", route.shard),
+            ));
+        }
+    }
+    if upstream.server_name.is_empty() {
+        return;
+    }
+    if upstream.dialect != UpstreamDialect::V2 {
+        errors.push(field_error(
+            &format!("{}.server_name", prefix),
+            "is only used with dialect v2",
+        ));
+    } else if webpki::DNSNameRef::try_from_ascii_str(&upstream.server_name).is_err() {
+        errors.push(field_error(
+            &format!("{}.server_name", prefix),
+            &format!("{} is not a DNS name", upstream.server_name),
+        ));
+    }
+}
+
+fn validate_addresses(
+    prefix: &str,
+    address: &str,
+    fallback_addresses: &[String],
+    errors: &mut Vec<FieldError>,
+) {
+    if address.parse::<SocketAddr>().is_err() {
+        errors.push(field_error(
+            &format!("{}.address", prefix),
+            &format!("{} is not of form x.x.x.x:p", address),
+        ));
+    }
+    for (i, fallback) in fallback_addresses.iter().enumerate() {
+        let listed = fallback == address || fallback_addresses[..i].contains(fallback);
+        if fallback.parse::<SocketAddr>().is_err() {
+            errors.push(field_error(
+                &format!("{}.fallback_addresses[{}]", prefix, i),
+                &format!("{} is not of form x.x.x.x:p", fallback),
+            ));
+        } else if listed {
+            errors.push(field_error(
+                &format!("{}.fallback_addresses[{}]", prefix, i),
+                &format!("{} is listed twice", fallback),
+            ));
+        }
+    }
+}
+
+fn validate_cert_files(
+    prefix: &str,
+    certificate: &str,
+    private_key: &str,
+    errors: &mut Vec<FieldError>,
+) {
+    if certificate.is_empty() {
+        errors.push(field_error(
+            &format!("{}.certificate", prefix),
+            "must not be empty",
+        ));
+    } else if !Path::new(certificate).is_file() {
+        errors.push(field_error(
+            &format!("{}.certificate", prefix),
+            &format!("{} is not a file", certificate),
+        ));
+    }
+    if private_key.is_empty() {
+        errors.push(field_error(
+            &format!("{}.private_key", prefix),
+            "must not be empty",
+        ));
+    } else if !Path::new(private_key).is_file() {
+        errors.push(field_error(
+            &format!("{}.private_key", prefix),
+            &format!("{} is not a file", private_key),
+        ));
+    }
+}
+
+fn field_error(field: &str, reason: &str) -> FieldError {
+    FieldError {
+        field: field.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Result<Config, ConfigError> {
+        Config::parse("test.toml".to_string(), content, false)
+    }
+
+    fn invalid_fields(content: &str) -> Vec<String> {
+        match parse(content) {
+            Err(ConfigError::Invalid(errors)) => errors.into_iter().map(|err| err.field).collect(),
+            Err(err) => panic!("{}", err),
+            Ok(_) => panic!("valid configuration"),
+        }
+    }
+
+    #[test]
+    fn minimal() {
+        let config = parse("www_root = \".\"\n[tls]\nmode = \"off\"\n").unwrap();
+        assert_eq!(config.listeners.len(), 1);
+        assert_eq!(config.listeners[0].role, ListenerRole::Http);
+        assert_eq!(config.upstream.address, SRV_ADDR);
+        assert_eq!(config.queue.capacity, QUEUE_CAPACITY);
+    }
+
+    #[test]
+    fn unknown_and_missing_fields() {
+        let unknown = parse("www_root = \".\"\nwww_rot = \".\"\n");
+        assert!(matches!(unknown, Err(ConfigError::Parse(..))));
+        let missing = parse("[tls]\nmode = \"off\"\n");
+        assert!(matches!(missing, Err(ConfigError::Parse(..))));
+    }
+
+    #[test]
+    fn acme_email() {
+        let base = "www_root = \".\"\n[tls]\ndomain = \"mles.io\"\n";
+        assert_eq!(invalid_fields(base), vec!["tls.email"]);
+        let bad = format!("{}email = \"nobody\"\n", base);
+        assert_eq!(invalid_fields(&bad), vec!["tls.email"]);
+        let good = format!("{}email = \"nobody@mles.io\"\n", base);
+        assert!(parse(&good).is_ok());
+    }
+
+    #[test]
+    fn every_bad_field() {
+        let content = r#"
+            www_root = "Cargo.toml"
+            [tls]
+            mode = "off"
+            [[listener]]
+            address = "localhost"
+            port = 0
+            role = "http"
+            [upstream]
+            address = "mles.io"
+            fallback_addresses = ["127.0.0.1:8077", "127.0.0.1:8077"]
+            [keepalive]
+            tcp = 0
+            [queue]
+            capacity = 0
+            [rate_limit]
+            connections = 10
+            interval = 0
+        "#;
+        assert_eq!(
+            invalid_fields(content),
+            vec![
+                "www_root",
+                "listener[0].address",
+                "listener[0].port",
+                "upstream.address",
+                "upstream.fallback_addresses[1]",
+                "keepalive.tcp",
+                "queue.capacity",
+                "rate_limit.interval",
+            ]
+        );
+        let err = parse(content).err().unwrap().to_string();
+        assert!(err.contains("upstream.address: mles.io is not of form x.x.x.x:p"));
+    }
+
+    #[test]
+    fn tls_policy() {
+        let base = "www_root = \".\"\n[tls]\nmode = \"dev\"\n";
+        let old = format!("{}min_version = \"1.1\"\n", base);
+        assert_eq!(invalid_fields(&old), vec!["tls.min_version"]);
+        let unknown = format!(
+            "{}cipher_suites = [\"TLS13_AES_256_GCM_SHA384\", \"RC4\"]\n",
+            base
+        );
+        assert_eq!(invalid_fields(&unknown), vec!["tls.cipher_suites"]);
+        let tls12_only = format!(
+            "{}min_version = \"1.3\"\ncipher_suites = [\"TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256\"]\n",
+            base
+        );
+        assert_eq!(invalid_fields(&tls12_only), vec!["tls.cipher_suites"]);
+        let good = format!(
+            "{}min_version = \"1.3\"\ncipher_suites = [\"TLS13_AES_256_GCM_SHA384\"]\n",
+            base
+        );
+        assert!(parse(&good).is_ok());
+    }
+
+    #[test]
+    fn shards_and_routes() {
+        let content = r#"
+            www_root = "."
+            [tls]
+            mode = "off"
+            [[upstream.shard]]
+            name = "default"
+            address = "127.0.0.1:8078"
+            [[upstream.shard]]
+            name = "eu"
+            address = "127.0.0.1:8079"
+            [[upstream.shard]]
+            name = "eu"
+            address = "127.0.0.1:8080"
+            [[upstream.route]]
+            channel = "eu-main"
+            prefix = "eu-"
+            shard = "eu"
+            [[upstream.route]]
+            regex = "("
+            shard = "eu"
+            [[upstream.route]]
+            prefix = "us-"
+            shard = "us"
+        "#;
+        assert_eq!(
+            invalid_fields(content),
+            vec![
+                "upstream.shard[0].name",
+                "upstream.shard[2].name",
+                "upstream.route[0]",
+                "upstream.route[1].regex",
+                "upstream.route[2].shard",
+            ]
+        );
+    }
+
+    #[test]
+    fn listeners() {
+        let content = r#"
+            www_root = "."
+            [tls]
+            mode = "files"
+            domain = "mles.io"
+            certificate = "Cargo.toml"
+            private_key = "Cargo.toml"
+            [[listener]]
+            address = "::"
+            port = 443
+            role = "tls"
+            client_addr = "forwarded"
+            [[listener]]
+            address = "::"
+            port = 443
+            role = "redirect"
+            redirect_port = 8443
+        "#;
+        assert_eq!(
+            invalid_fields(content),
+            vec!["listener[0].client_addr", "listener[1]"]
+        );
+    }
+
+    #[test]
+    fn dev_mode() {
+        let content = r#"
+            www_root = "."
+            [[listener]]
+            address = "::"
+            port = 80
+            role = "acme"
+            [[listener]]
+            address = "::"
+            port = 443
+            role = "tls"
+        "#;
+        assert_eq!(invalid_fields(content), vec!["tls.email", "tls.domain"]);
+        let config = Config::parse("test.toml".to_string(), content, true).unwrap();
+        assert_eq!(config.tls.mode, TlsMode::Dev);
+        assert_eq!(config.tls.domain, "localhost");
+        assert_eq!(config.listeners[0].role, ListenerRole::Redirect);
+    }
+
+    #[test]
+    fn env_override() {
+        let name = "MLES_WEBPROXY_TEST_OVERRIDE";
+        let mut value = "file".to_string();
+        override_from_env(name, &mut value);
+        assert_eq!(value, "file");
+        env::set_var(name, "env");
+        override_from_env(name, &mut value);
+        env::remove_var(name);
+        assert_eq!(value, "env");
+    }
+}
@@ -0,0 +1,231 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ *  Copyright (C) 2020  Mles developers
+ */
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, IsCa, KeyPair,
+    SanType,
+};
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+use crate::config::Host;
+use crate::storage::Storage;
+
+/// Names which are always included in a development certificate
+const DEV_NAMES: &[&str] = &["localhost", "127.0.0.1", "::1"];
+const CA_NAME: &str = "Mles WebProxy Development CA";
+const CA_CERT: &str = "dev-ca.pem";
+const CA_KEY: &str = "dev-ca.key";
+
+/// Returns the certificate of the local CA, which can be added to the trust
+/// store of browsers and test clients.
+pub fn ca_file(storage: &Storage) -> PathBuf {
+    storage.path(CA_CERT)
+}
+
+/// Generates a certificate for the host, `localhost` and the loopback
+/// addresses. It is self-signed or, with `local_ca`, signed by a CA which
+/// is created in the state directory on first use and kept over restarts.
+pub fn generate(storage: &Storage, host: &Host, local_ca: bool) -> Result<(), String> {
+    let mut names: Vec<String> = DEV_NAMES.iter().map(|name| name.to_string()).collect();
+    for name in Some(&host.domain).into_iter().chain(host.alt_names.iter()) {
+        if !names.contains(name) {
+            names.push(name.clone());
+        }
+    }
+
+    let mut params = CertificateParams::default();
+    params.serial_number = Some(serial_number()?);
+    params.distinguished_name = DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, host.domain.clone());
+    params.subject_alt_names = names
+        .into_iter()
+        .map(|name| match name.parse::<IpAddr>() {
+            Ok(addr) => SanType::IpAddress(addr),
+            Err(_) => SanType::DnsName(name),
+        })
+        .collect();
+    let cert = Certificate::from_params(params).map_err(|err| err.to_string())?;
+    let pem = if local_ca {
+        cert.serialize_pem_with_signer(&local_ca_cert(storage)?)
+    } else {
+        cert.serialize_pem()
+    }
+    .map_err(|err| err.to_string())?;
+
+    storage
+        .write_private(&host.key_name, cert.serialize_private_key_pem().as_bytes())
+        .map_err(|err| format!("{}: {}", host.key_name, err))?;
+    fs::write(&host.pem_name, pem).map_err(|err| format!("{}: {}", host.pem_name, err))?;
+    Ok(())
+}
+
+/// Loads the key of the local CA or creates a new CA. The certificate is
+/// rebuilt from the same name and key, so it still verifies against the
+/// one written on first use.
+fn local_ca_cert(storage: &Storage) -> Result<Certificate, String> {
+    let cert_file = storage.path(CA_CERT);
+    let key_file = storage.path(CA_KEY);
+    let key_pair = if key_file.is_file() {
+        let pem = storage
+            .read_private(&key_file)
+            .map_err(|err| format!("{}: {}", key_file.display(), err))?;
+        let pem = String::from_utf8(pem).map_err(|err| err.to_string())?;
+        Some(KeyPair::from_pem(&pem).map_err(|err| err.to_string())?)
+    } else {
+        None
+    };
+    let created = key_pair.is_none();
+
+    let mut params = CertificateParams::default();
+    params.serial_number = Some(serial_number()?);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, CA_NAME);
+    params.key_pair = key_pair;
+    let ca = Certificate::from_params(params).map_err(|err| err.to_string())?;
+
+    if created {
+        storage
+            .write_private(&key_file, ca.serialize_private_key_pem().as_bytes())
+            .map_err(|err| format!("{}: {}", key_file.display(), err))?;
+    }
+    if created || !cert_file.is_file() {
+        let pem = ca.serialize_pem().map_err(|err| err.to_string())?;
+        fs::write(&cert_file, pem).map_err(|err| format!("{}: {}", cert_file.display(), err))?;
+        println!("Created development CA {}", cert_file.display());
+    }
+    Ok(ca)
+}
+
+/// Browsers reject different certificates with the same issuer and serial.
+fn serial_number() -> Result<u64, String> {
+    let mut serial = [0u8; 8];
+    openssl::rand::rand_bytes(&mut serial).map_err(|err| err.to_string())?;
+    /* Keep the serial positive */
+    Ok(u64::from_be_bytes(serial) >> 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{StorageConfig, UpstreamConfig};
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::{X509NameRef, X509};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::path::Path;
+
+    fn state(dir: &Path, passphrase: &str) -> Storage {
+        let storage = Storage::new(&StorageConfig {
+            dir: dir.join("state").to_string_lossy().into_owned(),
+            passphrase: passphrase.to_string(),
+        });
+        storage.create_dir().unwrap();
+        storage
+    }
+
+    fn host(dir: &Path, domain: &str, alt_names: &[&str]) -> Host {
+        Host {
+            domain: domain.to_string(),
+            alt_names: alt_names.iter().map(|name| name.to_string()).collect(),
+            pem_name: dir
+                .join(format!("{}.pem", domain))
+                .to_string_lossy()
+                .into_owned(),
+            key_name: dir
+                .join(format!("{}.key", domain))
+                .to_string_lossy()
+                .into_owned(),
+            www_root: String::new(),
+            upstream: UpstreamConfig::default(),
+        }
+    }
+
+    fn read_cert<P: AsRef<Path>>(path: P) -> X509 {
+        X509::from_pem(&fs::read(path).unwrap()).unwrap()
+    }
+
+    fn common_name(name: &X509NameRef) -> String {
+        let entry = name.entries_by_nid(Nid::COMMONNAME).next().unwrap();
+        String::from_utf8(entry.data().as_slice().to_vec()).unwrap()
+    }
+
+    fn alt_names(cert: &X509) -> Vec<String> {
+        cert.subject_alt_names()
+            .unwrap()
+            .iter()
+            .map(|name| match (name.dnsname(), name.ipaddress()) {
+                (Some(dns), _) => dns.to_string(),
+                (None, Some(ip)) if ip.len() == 4 => {
+                    Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]).to_string()
+                }
+                (None, Some(ip)) => {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(ip);
+                    Ipv6Addr::from(octets).to_string()
+                }
+                (None, None) => panic!("unexpected alt name"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn self_signed_covers_names() {
+        let tmp = tempfile::tempdir().unwrap();
+        let storage = state(tmp.path(), "");
+        let host = host(tmp.path(), "proxy.test", &["www.proxy.test", "localhost"]);
+        generate(&storage, &host, false).unwrap();
+
+        let cert = read_cert(&host.pem_name);
+        assert_eq!(
+            alt_names(&cert),
+            vec![
+                "localhost",
+                "127.0.0.1",
+                "::1",
+                "proxy.test",
+                "www.proxy.test"
+            ]
+        );
+        assert_eq!(common_name(cert.subject_name()), "proxy.test");
+        assert_eq!(common_name(cert.issuer_name()), "proxy.test");
+        let key = PKey::private_key_from_pem(&fs::read(&host.key_name).unwrap()).unwrap();
+        assert!(key.public_eq(&cert.public_key().unwrap()));
+        assert!(cert.verify(&key).unwrap());
+        assert!(!ca_file(&storage).exists());
+    }
+
+    #[test]
+    fn local_ca_is_reused() {
+        let tmp = tempfile::tempdir().unwrap();
+        let storage = state(tmp.path(), "secret");
+        let first = host(tmp.path(), "first.test", &[]);
+        generate(&storage, &first, true).unwrap();
+        let ca_pem = fs::read(ca_file(&storage)).unwrap();
+
+        let second = host(tmp.path(), "second.test", &[]);
+        generate(&storage, &second, true).unwrap();
+        assert_eq!(fs::read(ca_file(&storage)).unwrap(), ca_pem);
+
+        let ca = read_cert(ca_file(&storage));
+        let ca_key = ca.public_key().unwrap();
+        assert_eq!(common_name(ca.subject_name()), CA_NAME);
+        for host in [&first, &second].iter() {
+            let leaf = read_cert(&host.pem_name);
+            assert_eq!(common_name(leaf.issuer_name()), CA_NAME);
+            assert!(leaf.verify(&ca_key).unwrap());
+            assert!(alt_names(&leaf).contains(&host.domain));
+        }
+        assert_ne!(
+            read_cert(&first.pem_name).serial_number().to_bn().unwrap(),
+            read_cert(&second.pem_name).serial_number().to_bn().unwrap()
+        );
+    }
+}
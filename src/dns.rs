@@ -0,0 +1,408 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ *  Copyright (C) 2020  Mles developers
+ */
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use std::net::{SocketAddr, UdpSocket};
+use std::process::Command;
+use std::time::{Duration, SystemTime};
+
+use crate::config::{DnsConfig, DnsProvider};
+
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+const DNS_MAXLEN: usize = 4096;
+const TXT_MAXLEN: usize = 255;
+const OPCODE_UPDATE: u16 = 5 << 11;
+const TYPE_SOA: u16 = 6;
+const TYPE_TXT: u16 = 16;
+const TYPE_TSIG: u16 = 250;
+const CLASS_IN: u16 = 1;
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+const TSIG_FUDGE: u16 = 300;
+
+/// Publishes and removes the TXT records of DNS-01 challenges.
+pub trait DnsSolver {
+    /// Adds a TXT record with the value, existing values of the name are kept.
+    fn set(&self, name: &str, value: &str) -> Result<(), String>;
+    /// Removes the TXT record with the value.
+    fn unset(&self, name: &str, value: &str) -> Result<(), String>;
+}
+
+pub fn solver(config: &DnsConfig) -> Box<dyn DnsSolver> {
+    match config.provider {
+        DnsProvider::Exec => Box::new(ExecSolver {
+            hook: config.hook.clone(),
+        }),
+        DnsProvider::Rfc2136 => Box::new(Rfc2136Solver {
+            server: config.server.parse().unwrap(), //already checked
+            zone: config.zone.clone(),
+            key: config.tsig_key.clone(),
+            algorithm: config.tsig_algorithm.clone(),
+            secret: base64::decode(&config.tsig_secret).unwrap_or_default(),
+            ttl: config.ttl,
+        }),
+    }
+}
+
+/// Runs `<hook> set|unset <name> <value>`, e.g. to call the API of a DNS provider.
+struct ExecSolver {
+    hook: String,
+}
+
+impl ExecSolver {
+    fn run(&self, action: &str, name: &str, value: &str) -> Result<(), String> {
+        let status = Command::new(&self.hook)
+            .arg(action)
+            .arg(name)
+            .arg(value)
+            .status()
+            .map_err(|err| format!("{}: {}", self.hook, err))?;
+        if !status.success() {
+            return Err(format!(
+                "{} {} {} failed: {}",
+                self.hook, action, name, status
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl DnsSolver for ExecSolver {
+    fn set(&self, name: &str, value: &str) -> Result<(), String> {
+        self.run("set", name, value)
+    }
+
+    fn unset(&self, name: &str, value: &str) -> Result<(), String> {
+        self.run("unset", name, value)
+    }
+}
+
+/// Dynamic updates (RFC 2136), signed with TSIG (RFC 8945) if a key is given.
+struct Rfc2136Solver {
+    server: SocketAddr,
+    zone: String,
+    key: String,
+    algorithm: String,
+    secret: Vec<u8>,
+    ttl: u32,
+}
+
+impl Rfc2136Solver {
+    fn update(&self, name: &str, value: &str, class: u16, ttl: u32) -> Result<(), String> {
+        let mut id = [0u8; 2];
+        openssl::rand::rand_bytes(&mut id).map_err(|err| err.to_string())?;
+        let id = u16::from_be_bytes(id);
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(|err| err.to_string())?
+            .as_secs();
+        let msg = self.message(id, now, name, value, class, ttl)?;
+
+        let bind_addr = if self.server.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind_addr).map_err(|err| err.to_string())?;
+        socket
+            .set_read_timeout(Some(DNS_TIMEOUT))
+            .map_err(|err| err.to_string())?;
+        socket
+            .send_to(&msg, self.server)
+            .map_err(|err| format!("{}: {}", self.server, err))?;
+        let mut resp = [0u8; DNS_MAXLEN];
+        loop {
+            let (len, from) = socket
+                .recv_from(&mut resp)
+                .map_err(|err| format!("{}: {}", self.server, err))?;
+            if from != self.server || len < 4 || resp[..2] != id.to_be_bytes() {
+                continue;
+            }
+            return match resp[3] & 0x0f {
+                0 => Ok(()),
+                rcode => Err(format!(
+                    "{}: update of {} failed: {}",
+                    self.server,
+                    name,
+                    rcode_str(rcode)
+                )),
+            };
+        }
+    }
+
+    /// Returns the update message, signed at the time if a key is given.
+    fn message(
+        &self,
+        id: u16,
+        now: u64,
+        name: &str,
+        value: &str,
+        class: u16,
+        ttl: u32,
+    ) -> Result<Vec<u8>, String> {
+        let mut rdata = Vec::new();
+        for chunk in value.as_bytes().chunks(TXT_MAXLEN) {
+            rdata.push(chunk.len() as u8);
+            rdata.extend_from_slice(chunk);
+        }
+
+        let mut msg = Vec::new();
+        put_u16(&mut msg, id);
+        put_u16(&mut msg, OPCODE_UPDATE);
+        put_u16(&mut msg, 1); // zone count
+        put_u16(&mut msg, 0); // prerequisite count
+        put_u16(&mut msg, 1); // update count
+        put_u16(&mut msg, 0); // additional count
+        put_name(&mut msg, &self.zone)?;
+        put_u16(&mut msg, TYPE_SOA);
+        put_u16(&mut msg, CLASS_IN);
+        put_name(&mut msg, name)?;
+        put_u16(&mut msg, TYPE_TXT);
+        put_u16(&mut msg, class);
+        put_u32(&mut msg, ttl);
+        put_u16(&mut msg, rdata.len() as u16);
+        msg.extend_from_slice(&rdata);
+        if !self.key.is_empty() {
+            self.sign(&mut msg, id, now)?;
+        }
+        Ok(msg)
+    }
+
+    /// Appends the TSIG record to the message.
+    fn sign(&self, msg: &mut Vec<u8>, id: u16, now: u64) -> Result<(), String> {
+        let digest = match self.algorithm.as_str() {
+            "hmac-sha1" => MessageDigest::sha1(),
+            "hmac-sha512" => MessageDigest::sha512(),
+            _ => MessageDigest::sha256(),
+        };
+        let time_signed = &now.to_be_bytes()[2..];
+
+        let mut vars = Vec::new();
+        put_name(&mut vars, &self.key.to_lowercase())?;
+        put_u16(&mut vars, CLASS_ANY);
+        put_u32(&mut vars, 0);
+        put_name(&mut vars, &self.algorithm)?;
+        vars.extend_from_slice(time_signed);
+        put_u16(&mut vars, TSIG_FUDGE);
+        put_u16(&mut vars, 0); // error
+        put_u16(&mut vars, 0); // other length
+
+        let pkey = PKey::hmac(&self.secret).map_err(|err| err.to_string())?;
+        let mut signer = Signer::new(digest, &pkey).map_err(|err| err.to_string())?;
+        signer.update(msg).map_err(|err| err.to_string())?;
+        signer.update(&vars).map_err(|err| err.to_string())?;
+        let mac = signer.sign_to_vec().map_err(|err| err.to_string())?;
+
+        let mut rdata = Vec::new();
+        put_name(&mut rdata, &self.algorithm)?;
+        rdata.extend_from_slice(time_signed);
+        put_u16(&mut rdata, TSIG_FUDGE);
+        put_u16(&mut rdata, mac.len() as u16);
+        rdata.extend_from_slice(&mac);
+        put_u16(&mut rdata, id);
+        put_u16(&mut rdata, 0); // error
+        put_u16(&mut rdata, 0); // other length
+
+        put_name(msg, &self.key.to_lowercase())?;
+        put_u16(msg, TYPE_TSIG);
+        put_u16(msg, CLASS_ANY);
+        put_u32(msg, 0);
+        put_u16(msg, rdata.len() as u16);
+        msg.extend_from_slice(&rdata);
+        /* The additional count includes TSIG only after signing */
+        msg[11] = 1;
+        Ok(())
+    }
+}
+
+impl DnsSolver for Rfc2136Solver {
+    fn set(&self, name: &str, value: &str) -> Result<(), String> {
+        self.update(name, value, CLASS_IN, self.ttl)
+    }
+
+    fn unset(&self, name: &str, value: &str) -> Result<(), String> {
+        self.update(name, value, CLASS_NONE, 0)
+    }
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_name(buf: &mut Vec<u8>, name: &str) -> Result<(), String> {
+    let labels = name.trim_end_matches('.');
+    /* The root has no labels, only the terminating zero */
+    if !labels.is_empty() {
+        for label in labels.split('.') {
+            if label.is_empty() || label.len() > 63 {
+                return Err(format!("{} is not a valid DNS name", name));
+            }
+            buf.push(label.len() as u8);
+            buf.extend_from_slice(label.as_bytes());
+        }
+    }
+    buf.push(0);
+    Ok(())
+}
+
+fn rcode_str(rcode: u8) -> String {
+    match rcode {
+        1 => "FORMERR".to_string(),
+        2 => "SERVFAIL".to_string(),
+        3 => "NXDOMAIN".to_string(),
+        4 => "NOTIMP".to_string(),
+        5 => "REFUSED".to_string(),
+        6 => "YXDOMAIN".to_string(),
+        7 => "YXRRSET".to_string(),
+        8 => "NXRRSET".to_string(),
+        9 => "NOTAUTH".to_string(),
+        10 => "NOTZONE".to_string(),
+        rcode => format!("RCODE {}", rcode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// Update adding "abc" to _acme-challenge.example.org, signed with
+    /// hmac-sha256 by update-key at 1600000000, encoded after RFC 2136 and
+    /// RFC 8945 with an independent implementation
+    const RECORDED: &[u8] = include_bytes!("../tests/data/rfc2136-update.bin");
+    const RECORDED_ID: u16 = 0x1234;
+    const RECORDED_TIME: u64 = 1_600_000_000;
+    const RECORDED_MAC: &str = "6fa4f7ce0c663090e4e69cdab857aa4ccb94271051dcc40a5aa059fbd6ba87b2";
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn solver(server: SocketAddr, key: &str, algorithm: &str) -> Rfc2136Solver {
+        Rfc2136Solver {
+            server,
+            zone: "example.org".to_string(),
+            key: key.to_string(),
+            algorithm: algorithm.to_string(),
+            secret: SECRET.to_vec(),
+            ttl: 60,
+        }
+    }
+
+    fn recorded_message(algorithm: &str) -> Vec<u8> {
+        let solver = solver("127.0.0.1:53".parse().unwrap(), "Update-Key", algorithm);
+        let name = "_acme-challenge.example.org";
+        solver
+            .message(RECORDED_ID, RECORDED_TIME, name, "abc", CLASS_IN, 60)
+            .unwrap()
+    }
+
+    /// MAC of a signed message, followed by the original id, error and other length
+    fn mac(msg: &[u8], len: usize) -> &[u8] {
+        &msg[msg.len() - 6 - len..msg.len() - 6]
+    }
+
+    #[test]
+    fn name_labels() {
+        let mut buf = Vec::new();
+        put_name(&mut buf, "_acme-challenge.example.org").unwrap();
+        assert_eq!(buf, b"\x0f_acme-challenge\x07example\x03org\x00".to_vec());
+        let mut fqdn = Vec::new();
+        put_name(&mut fqdn, "_acme-challenge.example.org.").unwrap();
+        assert_eq!(fqdn, buf);
+    }
+
+    #[test]
+    fn name_root() {
+        let mut buf = Vec::new();
+        put_name(&mut buf, ".").unwrap();
+        assert_eq!(buf, vec![0]);
+    }
+
+    #[test]
+    fn name_invalid() {
+        let mut buf = Vec::new();
+        assert!(put_name(&mut buf, "example..org").is_err());
+        assert!(put_name(&mut buf, &format!("{}.org", "a".repeat(64))).is_err());
+        assert!(put_name(&mut buf, &format!("{}.org", "a".repeat(63))).is_ok());
+    }
+
+    #[test]
+    fn recorded_update() {
+        assert_eq!(recorded_message("hmac-sha256"), RECORDED.to_vec());
+    }
+
+    #[test]
+    fn tsig_mac() {
+        let expected: Vec<u8> = (0..RECORDED_MAC.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&RECORDED_MAC[i..i + 2], 16).unwrap())
+            .collect();
+        let msg = recorded_message("hmac-sha256");
+        assert_eq!(mac(&msg, 32), &expected[..]);
+        assert_eq!(msg[msg.len() - 6..msg.len() - 4], RECORDED_ID.to_be_bytes());
+
+        /* The MAC length follows the algorithm */
+        let msg = recorded_message("hmac-sha1");
+        let len = u16::from_be_bytes([msg[msg.len() - 28], msg[msg.len() - 27]]);
+        assert_eq!(len, 20);
+        let msg = recorded_message("hmac-sha512");
+        let len = u16::from_be_bytes([msg[msg.len() - 72], msg[msg.len() - 71]]);
+        assert_eq!(len, 64);
+    }
+
+    #[test]
+    fn unsigned_long_value() {
+        let solver = solver("127.0.0.1:53".parse().unwrap(), "", "hmac-sha256");
+        let value = "v".repeat(300);
+        let msg = solver
+            .message(1, 0, "example.org", &value, CLASS_NONE, 0)
+            .unwrap();
+        /* No TSIG record */
+        assert_eq!(msg[10..12], [0, 0]);
+        /* The value is split into character strings of at most 255 bytes */
+        let rdata = &msg[msg.len() - 302..];
+        assert_eq!(rdata[0], 255);
+        assert_eq!(rdata[256], 45);
+        assert_eq!(msg[msg.len() - 304..msg.len() - 302], 302u16.to_be_bytes());
+    }
+
+    #[test]
+    fn update_local_server() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let target = thread::spawn(move || {
+            let mut updates = Vec::new();
+            /* Accept the first update and refuse the second one */
+            for rcode in [0u8, 5].iter() {
+                let mut buf = [0u8; DNS_MAXLEN];
+                let (len, from) = server.recv_from(&mut buf).unwrap();
+                let resp = [buf[0], buf[1], 0xa8, *rcode, 0, 0, 0, 0, 0, 0, 0, 0];
+                server.send_to(&resp, from).unwrap();
+                updates.push(buf[..len].to_vec());
+            }
+            updates
+        });
+
+        let solver = solver(addr, "update-key", "hmac-sha256");
+        let name = "_acme-challenge.example.org";
+        assert_eq!(solver.set(name, "abc"), Ok(()));
+        let err = solver.unset(name, "abc").unwrap_err();
+        assert!(err.contains("REFUSED"), "{}", err);
+
+        let updates = target.join().unwrap();
+        assert_eq!(updates[0][2..4], OPCODE_UPDATE.to_be_bytes());
+        /* Signed and with the class of the action */
+        assert_eq!(updates[0][10..12], [0, 1]);
+        let (set, unset) = (&updates[0], &updates[1]);
+        let class_at = 12 + 13 + 4 + 29 + 2;
+        assert_eq!(set[class_at..class_at + 2], CLASS_IN.to_be_bytes());
+        assert_eq!(unset[class_at..class_at + 2], CLASS_NONE.to_be_bytes());
+    }
+}
@@ -0,0 +1,325 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ *  Copyright (C) 2020  Mles developers
+ */
+use futures::{try_ready, Async, Future, Poll};
+use std::io::{self, Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio_io::AsyncRead;
+use warp::filters::BoxedFilter;
+use warp::Filter;
+
+use crate::config::{ClientAddrSource, ListenerConfig};
+use crate::net::{ClientConn, PrefixedStream};
+
+const PROXY_V1_MAXLEN: usize = 107;
+const PROXY_V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const PROXY_V2_HDRLEN: usize = 16;
+const HEADER_MAXLEN: usize = 4096;
+
+/// Filter which extracts the real client address as configured for the
+/// listener, `peer` extracts the address of the connection.
+pub fn client_addr(
+    listener: &ListenerConfig,
+    peer: BoxedFilter<(Option<SocketAddr>,)>,
+) -> BoxedFilter<(Option<SocketAddr>,)> {
+    match listener.client_addr {
+        /* The address of a PROXY protocol header is carried as the peer */
+        ClientAddrSource::Peer | ClientAddrSource::ProxyProtocol => peer,
+        ClientAddrSource::Forwarded => {
+            let trusted: Vec<IpAddr> = listener
+                .trusted_proxies
+                .iter()
+                .filter_map(|proxy| proxy.parse::<IpAddr>().ok())
+                .collect();
+            peer.and(warp::header::optional::<String>("forwarded"))
+                .and(warp::header::optional::<String>("x-forwarded-for"))
+                .map(
+                    move |peer: Option<SocketAddr>, fwd: Option<String>, xff: Option<String>| {
+                        let peer = peer?;
+                        if !trusted.contains(&peer.ip()) {
+                            return Some(peer);
+                        }
+                        let forwarded = fwd.as_ref().and_then(|fwd| parse_forwarded(fwd));
+                        let forwarded_for = xff.as_ref().and_then(|xff| parse_x_forwarded_for(xff));
+                        forwarded.or(forwarded_for).or(Some(peer))
+                    },
+                )
+                .boxed()
+        }
+    }
+}
+
+/// Parses the last for= element of a Forwarded header (RFC 7239).
+fn parse_forwarded(value: &str) -> Option<SocketAddr> {
+    value
+        .split(',')
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let mut kv = pair.trim().splitn(2, '=');
+                let key = kv.next()?;
+                if !key.eq_ignore_ascii_case("for") {
+                    return None;
+                }
+                parse_node(kv.next()?)
+            })
+        })
+        .last()
+}
+
+/// Parses the last address of an X-Forwarded-For header, the one added by the trusted proxy.
+fn parse_x_forwarded_for(value: &str) -> Option<SocketAddr> {
+    value.rsplit(',').next().and_then(parse_node)
+}
+
+fn parse_node(node: &str) -> Option<SocketAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+    let ip = node.trim_start_matches('[').trim_end_matches(']');
+    ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
+}
+
+/// Reads the PROXY protocol header which precedes the connection. The
+/// client address of the header is carried on the connection.
+pub fn read_proxy_header<S: AsyncRead>(
+    stream: S,
+) -> impl Future<Item = ClientConn<PrefixedStream<S>>, Error = io::Error> {
+    ProxyHeader {
+        stream: Some(stream),
+        buf: Vec::new(),
+    }
+    .map_err(|err| {
+        println!("PROXY protocol handshake failed: {}", err);
+        err
+    })
+}
+
+struct ProxyHeader<S> {
+    stream: Option<S>,
+    buf: Vec<u8>,
+}
+
+impl<S: AsyncRead> Future for ProxyHeader<S> {
+    type Item = ClientConn<PrefixedStream<S>>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        loop {
+            if let Some((hdr_len, addr)) = parse_proxy_header(&self.buf)? {
+                /* Whatever follows the header belongs to the requests */
+                let rest = self.buf.split_off(hdr_len);
+                let stream = self.stream.take().unwrap();
+                return Ok(Async::Ready(ClientConn::new(
+                    PrefixedStream::new(rest, stream),
+                    addr,
+                )));
+            }
+            if self.buf.len() >= HEADER_MAXLEN {
+                return Err(Error::new(ErrorKind::InvalidData, "Too long PROXY header"));
+            }
+            let mut tmp = [0u8; 1024];
+            let stream = self.stream.as_mut().unwrap();
+            let len = try_ready!(stream.poll_read(&mut tmp));
+            if 0 == len {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Closed in handshake"));
+            }
+            self.buf.extend_from_slice(&tmp[..len]);
+        }
+    }
+}
+
+/// Returns the length of a complete PROXY protocol header and the source address in it.
+fn parse_proxy_header(buf: &[u8]) -> io::Result<Option<(usize, Option<SocketAddr>)>> {
+    let invalid = |reason| Err(Error::new(ErrorKind::InvalidData, reason));
+
+    if buf.len() < PROXY_V2_SIGNATURE.len() {
+        let len = buf.len();
+        if !PROXY_V2_SIGNATURE.starts_with(buf) && !b"PROXY ".starts_with(&buf[..len.min(6)]) {
+            return invalid("Not a PROXY protocol header");
+        }
+        return Ok(None);
+    }
+    if buf.starts_with(PROXY_V2_SIGNATURE) {
+        if buf.len() < PROXY_V2_HDRLEN {
+            return Ok(None);
+        }
+        let ver_cmd = buf[12];
+        let family = buf[13];
+        let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+        if buf.len() < PROXY_V2_HDRLEN + len {
+            return Ok(None);
+        }
+        if ver_cmd >> 4 != 2 {
+            return invalid("Unsupported PROXY protocol version");
+        }
+        let addrs = &buf[PROXY_V2_HDRLEN..PROXY_V2_HDRLEN + len];
+        let addr = match (ver_cmd & 0x0f, family >> 4) {
+            /* LOCAL command carries no client */
+            (0, _) => None,
+            (1, 1) if addrs.len() >= 12 => {
+                let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+                let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+                Some(SocketAddr::new(IpAddr::V4(ip), port))
+            }
+            (1, 2) if addrs.len() >= 36 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&addrs[0..16]);
+                let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+                Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port))
+            }
+            (1, _) => None,
+            _ => return invalid("Unsupported PROXY protocol command"),
+        };
+        return Ok(Some((PROXY_V2_HDRLEN + len, addr)));
+    }
+    if !buf.starts_with(b"PROXY ") {
+        return invalid("Not a PROXY protocol header");
+    }
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() >= PROXY_V1_MAXLEN => return invalid("Too long PROXY header"),
+        None => return Ok(None),
+    };
+    let line = match std::str::from_utf8(&buf[..end]) {
+        Ok(line) => line,
+        Err(_) => return invalid("Invalid PROXY header"),
+    };
+    let fields: Vec<&str> = line.split(' ').collect();
+    let addr = match fields.as_slice() {
+        ["PROXY", "TCP4", src, _dst, sport, _dport]
+        | ["PROXY", "TCP6", src, _dst, sport, _dport] => {
+            match (src.parse::<IpAddr>(), sport.parse::<u16>()) {
+                (Ok(ip), Ok(port)) => Some(SocketAddr::new(ip, port)),
+                _ => return invalid("Invalid PROXY header address"),
+            }
+        }
+        ["PROXY", "UNKNOWN", ..] => None,
+        _ => return invalid("Invalid PROXY header"),
+    };
+    Ok(Some((end + 2, addr)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn v2_header(ver_cmd: u8, family: u8, addrs: &[u8]) -> Vec<u8> {
+        let mut buf = PROXY_V2_SIGNATURE.to_vec();
+        buf.push(ver_cmd);
+        buf.push(family);
+        buf.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+        buf.extend_from_slice(addrs);
+        buf
+    }
+
+    #[test]
+    fn proxy_v1() {
+        let buf = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        let (len, addr) = parse_proxy_header(buf).unwrap().unwrap();
+        assert_eq!(len, 45);
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+
+        let buf = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        let (_, addr) = parse_proxy_header(buf).unwrap().unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        let (len, addr) = parse_proxy_header(b"PROXY UNKNOWN\r\n").unwrap().unwrap();
+        assert_eq!((len, addr), (15, None));
+    }
+
+    #[test]
+    fn proxy_v2() {
+        let mut addrs = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        addrs.extend_from_slice(&56324u16.to_be_bytes());
+        addrs.extend_from_slice(&443u16.to_be_bytes());
+        let buf = v2_header(0x21, 0x11, &addrs);
+        let (len, addr) = parse_proxy_header(&buf).unwrap().unwrap();
+        assert_eq!(len, PROXY_V2_HDRLEN + 12);
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+
+        let mut addrs = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        addrs.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        addrs.extend_from_slice(&56324u16.to_be_bytes());
+        addrs.extend_from_slice(&443u16.to_be_bytes());
+        let buf = v2_header(0x21, 0x21, &addrs);
+        let (_, addr) = parse_proxy_header(&buf).unwrap().unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        /* LOCAL command of health checks */
+        let buf = v2_header(0x20, 0x00, &[]);
+        let (len, addr) = parse_proxy_header(&buf).unwrap().unwrap();
+        assert_eq!((len, addr), (PROXY_V2_HDRLEN, None));
+    }
+
+    #[test]
+    fn proxy_truncated() {
+        let v1 = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n";
+        let v2 = v2_header(0x21, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0, 80, 1, 187]);
+        for len in 0..v1.len() {
+            assert!(parse_proxy_header(&v1[..len]).unwrap().is_none());
+        }
+        for len in 0..v2.len() {
+            assert!(parse_proxy_header(&v2[..len]).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn proxy_garbage() {
+        assert!(parse_proxy_header(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(parse_proxy_header(b"PROXY TCP4 garbage\r\n").is_err());
+        assert!(parse_proxy_header(b"PROXY TCP4 192.0.2.1 198.51.100.1 x 443\r\n").is_err());
+        assert!(parse_proxy_header(&[b'P'; 64]).is_err());
+        let long = [&b"PROXY "[..], &[b'1'; PROXY_V1_MAXLEN]].concat();
+        assert!(parse_proxy_header(&long).is_err());
+        assert!(parse_proxy_header(&v2_header(0x11, 0x11, &[0; 12])).is_err());
+        assert!(parse_proxy_header(&v2_header(0x2f, 0x11, &[0; 12])).is_err());
+    }
+
+    #[test]
+    fn proxy_header_keeps_request() {
+        let buf = &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n"[..];
+        let mut conn = read_proxy_header(buf).wait().unwrap();
+        assert_eq!(conn.remote_addr(), Some("192.0.2.1:56324".parse().unwrap()));
+        let mut request = String::new();
+        conn.read_to_string(&mut request).unwrap();
+        assert_eq!(request, "GET / HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn forwarded() {
+        assert_eq!(
+            parse_forwarded("for=192.0.2.1"),
+            Some("192.0.2.1:0".parse().unwrap())
+        );
+        assert_eq!(
+            parse_forwarded("for=192.0.2.1;proto=https, For=\"[2001:db8::1]:4711\""),
+            Some("[2001:db8::1]:4711".parse().unwrap())
+        );
+        assert_eq!(
+            parse_forwarded("proto=https;for=\"192.0.2.1:4711\";by=198.51.100.1"),
+            Some("192.0.2.1:4711".parse().unwrap())
+        );
+        assert_eq!(parse_forwarded("for=unknown"), None);
+        assert_eq!(parse_forwarded("proto=https"), None);
+        assert_eq!(parse_forwarded(""), None);
+    }
+
+    #[test]
+    fn x_forwarded_for() {
+        assert_eq!(
+            parse_x_forwarded_for("203.0.113.7, 192.0.2.1"),
+            Some("192.0.2.1:0".parse().unwrap())
+        );
+        assert_eq!(
+            parse_x_forwarded_for("2001:db8::1"),
+            Some("[2001:db8::1]:0".parse().unwrap())
+        );
+        assert_eq!(parse_x_forwarded_for("203.0.113.7, garbage"), None);
+        assert_eq!(parse_x_forwarded_for(""), None);
+    }
+}
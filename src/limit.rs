@@ -0,0 +1,133 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ *  Copyright (C) 2020  Mles developers
+ */
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::RateLimitConfig;
+
+/// Limits the WebSocket connections each client address opens within an
+/// interval. The address is the real client address of the listener, so
+/// clients behind a reverse proxy are limited one by one.
+pub struct RateLimiter {
+    connections: u32,
+    interval: Duration,
+    clients: Mutex<Clients>,
+}
+
+struct Clients {
+    windows: HashMap<IpAddr, Window>,
+    pruned: Instant,
+}
+
+struct Window {
+    start: Instant,
+    count: u32,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        RateLimiter {
+            connections: config.connections,
+            interval: config.interval_duration(),
+            clients: Mutex::new(Clients {
+                windows: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Counts a connection of the client, false if the client is over its limit.
+    pub fn allow(&self, ip: IpAddr) -> bool {
+        self.allow_at(ip, Instant::now())
+    }
+
+    fn allow_at(&self, ip: IpAddr, now: Instant) -> bool {
+        if self.connections == 0 {
+            return true;
+        }
+        let interval = self.interval;
+        let mut clients = self.clients.lock().unwrap();
+        /* Forget the clients whose interval has passed */
+        if now.duration_since(clients.pruned) >= interval {
+            clients
+                .windows
+                .retain(|_, window| now.duration_since(window.start) < interval);
+            clients.pruned = now;
+        }
+        let window = clients.windows.entry(ip).or_insert(Window {
+            start: now,
+            count: 0,
+        });
+        if now.duration_since(window.start) >= interval {
+            window.start = now;
+            window.count = 0;
+        }
+        if window.count >= self.connections {
+            return false;
+        }
+        window.count += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(connections: u32) -> RateLimiter {
+        RateLimiter::new(&RateLimitConfig {
+            connections,
+            interval: 60,
+        })
+    }
+
+    #[test]
+    fn limits_each_client() {
+        let limiter = limiter(2);
+        let now = Instant::now();
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "2001:db8::1".parse().unwrap();
+        assert!(limiter.allow_at(client, now));
+        assert!(limiter.allow_at(client, now));
+        assert!(!limiter.allow_at(client, now));
+        assert!(limiter.allow_at(other, now));
+    }
+
+    #[test]
+    fn allows_again_after_interval() {
+        let limiter = limiter(1);
+        let now = Instant::now();
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(limiter.allow_at(client, now));
+        assert!(!limiter.allow_at(client, now + Duration::from_secs(59)));
+        assert!(limiter.allow_at(client, now + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn forgets_passed_clients() {
+        let limiter = limiter(1);
+        let now = Instant::now();
+        for i in 0..10u8 {
+            assert!(limiter.allow_at(IpAddr::from([192, 0, 2, i]), now));
+        }
+        let later = now + Duration::from_secs(61);
+        assert!(limiter.allow_at("192.0.2.100".parse().unwrap(), later));
+        assert_eq!(limiter.clients.lock().unwrap().windows.len(), 1);
+    }
+
+    #[test]
+    fn zero_is_unlimited() {
+        let limiter = limiter(0);
+        let now = Instant::now();
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        for _ in 0..100 {
+            assert!(limiter.allow_at(client, now));
+        }
+    }
+}
@@ -0,0 +1,145 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ *  Copyright (C) 2020  Mles developers
+ */
+use futures::{Future, Poll, Stream};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::timer::Timeout;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts connections and runs the handshake of each one in a task of its
+/// own, so that slow or idle clients hold up neither the listener nor the
+/// other clients. The established connections are passed to `serve`.
+pub fn accept<H, F, R, S>(
+    addr: &SocketAddr,
+    handshake: H,
+    serve: R,
+) -> io::Result<impl Future<Item = (), Error = ()> + Send>
+where
+    H: Fn(TcpStream) -> F + Send + 'static,
+    F: Future<Item = Option<ClientConn<S>>, Error = io::Error> + Send + 'static,
+    R: Fn(ClientConn<S>) + Send + Sync + 'static,
+    S: Send + 'static,
+{
+    let listener = TcpListener::bind(addr)?;
+    let serve = Arc::new(serve);
+    Ok(listener
+        .incoming()
+        .then(|res| Ok::<_, ()>(res.ok()))
+        .filter_map(|stream| stream)
+        .for_each(move |stream| {
+            let serve = serve.clone();
+            let conn = Timeout::new(handshake(stream), HANDSHAKE_TIMEOUT).then(move |res| {
+                if let Ok(Some(conn)) = res {
+                    serve(conn);
+                }
+                Ok(())
+            });
+            tokio::spawn(conn);
+            Ok(())
+        }))
+}
+
+/// A connection with the address of its client, the peer or the one told
+/// by a proxy in front.
+pub struct ClientConn<S> {
+    inner: S,
+    remote_addr: Option<SocketAddr>,
+}
+
+impl<S> ClientConn<S> {
+    pub fn new(inner: S, remote_addr: Option<SocketAddr>) -> Self {
+        ClientConn { inner, remote_addr }
+    }
+
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+}
+
+impl<S: Read> Read for ClientConn<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Write> Write for ClientConn<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for ClientConn<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for ClientConn<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+/// A stream which returns already consumed bytes before reading from the inner stream.
+pub struct PrefixedStream<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> PrefixedStream<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Self {
+        PrefixedStream {
+            prefix,
+            pos: 0,
+            inner,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: Read> Read for PrefixedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.prefix.len() {
+            let len = std::cmp::min(buf.len(), self.prefix.len() - self.pos);
+            buf[..len].copy_from_slice(&self.prefix[self.pos..self.pos + len]);
+            self.pos += len;
+            if self.pos == self.prefix.len() {
+                self.prefix = Vec::new();
+                self.pos = 0;
+            }
+            return Ok(len);
+        }
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Write> Write for PrefixedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for PrefixedStream<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for PrefixedStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
@@ -0,0 +1,231 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ *  Copyright (C) 2020  Mles developers
+ */
+use openssl::hash::MessageDigest;
+use openssl::ocsp::{
+    OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse, OcspResponseStatus,
+};
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::verify::X509VerifyFlags;
+use openssl::x509::X509;
+use std::io::Read;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio_rustls::rustls::Certificate;
+
+use crate::config::Host;
+use crate::tls::CertResolver;
+
+/// How often replaced certificates are looked for
+const OCSP_CHECK: Duration = Duration::from_secs(60);
+const OCSP_REFRESH: Duration = Duration::from_secs(12 * 60 * 60);
+const OCSP_RETRY: Duration = Duration::from_secs(10 * 60);
+const OCSP_TIMEOUT_MS: u64 = 10_000;
+/// Allowed clock skew of the responder in seconds
+const OCSP_LEEWAY: u32 = 300;
+
+/// Fetches the OCSP responses of the certificates in use and staples them,
+/// refreshing them in the background. A response which can no longer be
+/// refreshed is stapled until it expires.
+pub fn run_stapling(resolver: Arc<CertResolver>, hosts: Vec<Host>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut stapled: Vec<Option<Certificate>> = hosts.iter().map(|_| None).collect();
+        let mut next: Vec<Instant> = hosts.iter().map(|_| Instant::now()).collect();
+        loop {
+            for (i, host) in hosts.iter().enumerate() {
+                let chain = match resolver.chain(&host.domain) {
+                    Some(chain) => chain,
+                    None => continue,
+                };
+                let replaced = chain.first() != stapled[i].as_ref();
+                if !replaced && next[i] > Instant::now() {
+                    continue;
+                }
+                stapled[i] = chain.first().cloned();
+                match fetch(&chain) {
+                    Ok(Some(response)) => {
+                        println!("OCSP response for {} stapled", host.domain);
+                        resolver.set_ocsp(&host.domain, chain[0].clone(), Some(response));
+                        next[i] = Instant::now() + OCSP_REFRESH;
+                    }
+                    Ok(None) => {
+                        println!("Certificate of {} has no OCSP responder", host.domain);
+                        resolver.set_ocsp(&host.domain, chain[0].clone(), None);
+                        next[i] = Instant::now() + OCSP_REFRESH;
+                    }
+                    Err(err) => {
+                        println!("OCSP err for {}: {}", host.domain, err);
+                        let valid = resolver
+                            .ocsp(&host.domain)
+                            .map_or(false, |response| check(&chain, &response).is_ok());
+                        if !valid || replaced {
+                            resolver.set_ocsp(&host.domain, chain[0].clone(), None);
+                        }
+                        next[i] = Instant::now() + OCSP_RETRY;
+                    }
+                }
+            }
+            thread::sleep(OCSP_CHECK);
+        }
+    })
+}
+
+/// Fetches the OCSP response of the first certificate of the chain, or
+/// returns None if the certificate names no responder.
+fn fetch(chain: &[Certificate]) -> Result<Option<Vec<u8>>, String> {
+    let (cert, issuer) = match parse_chain(chain)? {
+        Some(pair) => pair,
+        None => return Ok(None),
+    };
+    let responders = cert.ocsp_responders().map_err(|err| err.to_string())?;
+    let url = match responders.iter().next() {
+        Some(url) => url.to_string(),
+        None => return Ok(None),
+    };
+
+    let id = OcspCertId::from_cert(MessageDigest::sha1(), &cert, &issuer)
+        .map_err(|err| err.to_string())?;
+    let mut request = OcspRequest::new().map_err(|err| err.to_string())?;
+    request.add_id(id).map_err(|err| err.to_string())?;
+    let request = request.to_der().map_err(|err| err.to_string())?;
+    let resp = ureq::post(&url)
+        .set("Content-Type", "application/ocsp-request")
+        .timeout_connect(OCSP_TIMEOUT_MS)
+        .timeout_read(OCSP_TIMEOUT_MS)
+        .send_bytes(&request);
+    if !resp.ok() {
+        return Err(format!("POST {} failed: {}", url, resp.status()));
+    }
+    let mut response = Vec::new();
+    resp.into_reader()
+        .read_to_end(&mut response)
+        .map_err(|err| format!("{}: {}", url, err))?;
+    check(chain, &response)?;
+    Ok(Some(response))
+}
+
+/// Checks that the response is signed by the issuer or its responder and
+/// tells the certificate is good and the response is still valid.
+fn check(chain: &[Certificate], response: &[u8]) -> Result<(), String> {
+    let (cert, issuer) = match parse_chain(chain)? {
+        Some(pair) => pair,
+        None => return Err("no issuer in the certificate chain".to_string()),
+    };
+    let response = OcspResponse::from_der(response).map_err(|err| err.to_string())?;
+    if response.status() != OcspResponseStatus::SUCCESSFUL {
+        return Err(format!(
+            "responder returned status {}",
+            response.status().as_raw()
+        ));
+    }
+    let basic = response.basic().map_err(|err| err.to_string())?;
+
+    let mut certs = Stack::new().map_err(|err| err.to_string())?;
+    certs.push(issuer.clone()).map_err(|err| err.to_string())?;
+    let mut store = X509StoreBuilder::new().map_err(|err| err.to_string())?;
+    store
+        .add_cert(issuer.clone())
+        .map_err(|err| err.to_string())?;
+    /* The issuer is trusted as is, the chain needs not reach a root */
+    store
+        .set_flags(X509VerifyFlags::PARTIAL_CHAIN)
+        .map_err(|err| err.to_string())?;
+    let store = store.build();
+    basic
+        .verify(&certs, &store, OcspFlag::empty())
+        .map_err(|err| format!("invalid signature: {}", err))?;
+
+    let id = OcspCertId::from_cert(MessageDigest::sha1(), &cert, &issuer)
+        .map_err(|err| err.to_string())?;
+    let status = match basic.find_status(&id) {
+        Some(status) => status,
+        None => return Err("no status for the certificate".to_string()),
+    };
+    if status.status == OcspCertStatus::REVOKED {
+        return Err("certificate is revoked".to_string());
+    }
+    if status.status != OcspCertStatus::GOOD {
+        return Err("certificate is unknown to the responder".to_string());
+    }
+    status
+        .check_validity(OCSP_LEEWAY, None)
+        .map_err(|_| "response is expired".to_string())
+}
+
+/// Returns the certificate and its issuer, None for a self-signed certificate.
+fn parse_chain(chain: &[Certificate]) -> Result<Option<(X509, X509)>, String> {
+    if chain.len() < 2 {
+        return Ok(None);
+    }
+    let cert = X509::from_der(&chain[0].0).map_err(|err| err.to_string())?;
+    let issuer = X509::from_der(&chain[1].0).map_err(|err| err.to_string())?;
+    Ok(Some((cert, issuer)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /* A test CA with a good and a revoked leaf, and responses of openssl ocsp
+     * signed by the CA which are valid until 2126 */
+    const CA: &[u8] = include_bytes!("../tests/data/ocsp-ca.der");
+    const OTHER_CA: &[u8] = include_bytes!("../tests/data/ocsp-other-ca.der");
+    const LEAF: &[u8] = include_bytes!("../tests/data/ocsp-leaf.der");
+    const REVOKED_LEAF: &[u8] = include_bytes!("../tests/data/ocsp-revoked-leaf.der");
+    const GOOD: &[u8] = include_bytes!("../tests/data/ocsp-good.der");
+    const REVOKED: &[u8] = include_bytes!("../tests/data/ocsp-revoked.der");
+
+    fn chain(certs: &[&[u8]]) -> Vec<Certificate> {
+        certs
+            .iter()
+            .map(|cert| Certificate(cert.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn good_response() {
+        assert_eq!(check(&chain(&[LEAF, CA]), GOOD), Ok(()));
+    }
+
+    #[test]
+    fn revoked_response() {
+        assert_eq!(
+            check(&chain(&[REVOKED_LEAF, CA]), REVOKED),
+            Err("certificate is revoked".to_string())
+        );
+    }
+
+    #[test]
+    fn response_of_another_certificate() {
+        assert_eq!(
+            check(&chain(&[REVOKED_LEAF, CA]), GOOD),
+            Err("no status for the certificate".to_string())
+        );
+    }
+
+    #[test]
+    fn response_not_signed_by_issuer() {
+        let err = check(&chain(&[LEAF, OTHER_CA]), GOOD).unwrap_err();
+        assert!(err.starts_with("invalid signature"), "{}", err);
+    }
+
+    #[test]
+    fn malformed_response() {
+        assert!(check(&chain(&[LEAF, CA]), b"not an ocsp response").is_err());
+        assert!(check(&chain(&[LEAF, CA]), &GOOD[..GOOD.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn self_signed_is_not_stapled() {
+        assert_eq!(
+            check(&chain(&[CA]), GOOD),
+            Err("no issuer in the certificate chain".to_string())
+        );
+        assert_eq!(fetch(&chain(&[CA])), Ok(None));
+    }
+}
@@ -0,0 +1,178 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ *  Copyright (C) 2020  Mles developers
+ */
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub const HEALTH_CHECK: Duration = Duration::from_secs(10);
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Mles servers of an upstream in failover order. Channels are connected
+/// to the first healthy server, as the clients of a channel have to meet
+/// on the same server.
+pub struct Pool {
+    servers: Vec<Server>,
+}
+
+struct Server {
+    addr: SocketAddr,
+    healthy: AtomicBool,
+}
+
+impl Pool {
+    pub fn new(addrs: &[SocketAddr]) -> Self {
+        Pool {
+            servers: addrs
+                .iter()
+                .map(|addr| Server {
+                    addr: *addr,
+                    healthy: AtomicBool::new(true),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.servers.iter().map(|server| server.addr).collect()
+    }
+
+    /// Returns the first healthy server, or the first server if none is
+    /// healthy.
+    pub fn select(&self) -> SocketAddr {
+        self.servers
+            .iter()
+            .find(|server| server.healthy.load(Ordering::Relaxed))
+            .unwrap_or(&self.servers[0])
+            .addr
+    }
+
+    /// Returns true if a server is healthy.
+    pub fn healthy(&self) -> bool {
+        self.servers
+            .iter()
+            .any(|server| server.healthy.load(Ordering::Relaxed))
+    }
+
+    /// Marks a server down after a failed connection until a health check
+    /// passes again.
+    pub fn mark_down(&self, addr: &SocketAddr, reason: &str) {
+        if let Some(server) = self.servers.iter().find(|server| server.addr == *addr) {
+            set_health(server, false, reason);
+        }
+    }
+
+    /// Checks the server with a connection to it.
+    fn check(&self, index: usize, connect: Connect) {
+        let server = &self.servers[index];
+        match connect(&server.addr) {
+            Ok(_) => set_health(server, true, "health check passed"),
+            Err(err) => set_health(server, false, &err.to_string()),
+        }
+    }
+}
+
+/// Connection of a health check
+type Connect = fn(&SocketAddr) -> io::Result<()>;
+
+/// Tries a TCP connection to the server.
+fn connect(addr: &SocketAddr) -> io::Result<()> {
+    TcpStream::connect_timeout(addr, HEALTH_TIMEOUT).map(|_| ())
+}
+
+/// Checks all servers of the pools at the same time, so that a round takes
+/// at most the timeout of one connection however many servers are down.
+fn check_all(pools: &[Arc<Pool>], connect: Connect) {
+    let mut checks = Vec::new();
+    for pool in pools.iter() {
+        for index in 0..pool.servers.len() {
+            let pool = pool.clone();
+            checks.push(thread::spawn(move || pool.check(index, connect)));
+        }
+    }
+    for check in checks {
+        let _ = check.join();
+    }
+}
+
+fn set_health(server: &Server, healthy: bool, reason: &str) {
+    if server.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+        let state = if healthy { "up" } else { "down" };
+        println!("Mles server {} is {}: {}", server.addr, state, reason);
+    }
+}
+
+/// Returns a pool of the servers, the one created earlier if there is
+/// one for the same servers.
+pub fn shared(addrs: &[SocketAddr], pools: &mut Vec<Arc<Pool>>) -> Arc<Pool> {
+    if let Some(pool) = pools.iter().find(|pool| pool.addrs() == addrs) {
+        return pool.clone();
+    }
+    let pool = Arc::new(Pool::new(addrs));
+    pools.push(pool.clone());
+    pool
+}
+
+/// Checks the servers of the pools in the background.
+pub fn run_health_checks(pools: Vec<Arc<Pool>>) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        check_all(&pools, connect);
+        thread::sleep(HEALTH_CHECK);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    #[test]
+    fn checks_servers_concurrently() {
+        let up = TcpListener::bind("127.0.0.1:0").unwrap();
+        let up_addr = up.local_addr().unwrap();
+        /* Nothing listens on the port of a dropped listener */
+        let down_addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let pools = vec![
+            Arc::new(Pool::new(&[down_addr, up_addr])),
+            Arc::new(Pool::new(&[up_addr])),
+        ];
+        check_all(&pools, connect);
+        assert_eq!(pools[0].select(), up_addr);
+        assert!(pools[0].healthy());
+        assert!(pools[1].healthy());
+    }
+
+    const SLOW: Duration = Duration::from_millis(500);
+
+    /// A connection which takes its whole timeout.
+    fn time_out(_: &SocketAddr) -> io::Result<()> {
+        thread::sleep(SLOW);
+        Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+    }
+
+    #[test]
+    fn round_is_bounded() {
+        let addrs: Vec<SocketAddr> = (0..8)
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], 8077 + port)))
+            .collect();
+        let pools = vec![
+            Arc::new(Pool::new(&addrs[..4])),
+            Arc::new(Pool::new(&addrs[4..])),
+        ];
+        let start = Instant::now();
+        check_all(&pools, time_out);
+        assert!(start.elapsed() < SLOW * 3);
+        assert!(!pools[0].healthy());
+        assert!(!pools[1].healthy());
+    }
+}
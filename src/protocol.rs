@@ -0,0 +1,281 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ *  Copyright (C) 2020  Mles developers
+ */
+use base64::{decode as b64decode, encode as b64encode};
+use mles_utils::Msg;
+use serde::{Deserialize, Serialize};
+use warp::filters::ws::Message;
+use warp::Filter;
+
+/// Close code for frames of a type the subprotocol does not use (RFC 6455)
+pub const CLOSE_UNSUPPORTED: u16 = 1003;
+/// Close code for frames whose content is not valid (RFC 6455)
+pub const CLOSE_INVALID: u16 = 1007;
+/// Close code when a queue of the session is full (RFC 6455 registry)
+pub const CLOSE_OVERLOADED: u16 = 1013;
+/// Close code when the Mles server cannot be reached
+pub const CLOSE_BAD_GATEWAY: u16 = 1014;
+/// Length of the AES nonce which starts each message
+pub const NONCE_LEN: usize = 16;
+
+/// WebSocket subprotocols spoken with the clients. Each one converts its
+/// frames to and from encoded Mles messages, so a new version is added by
+/// registering it in SUBPROTOCOLS with its own conversions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Subprotocol {
+    /// CBOR encoded Msg in binary frames, used by MlesTalk
+    MlesWebsocket,
+    /// JsonMsg in text frames, for clients without CBOR
+    MlesJson,
+}
+
+/// Subprotocols accepted from the clients.
+pub const SUBPROTOCOLS: &[Subprotocol] = &[Subprotocol::MlesWebsocket, Subprotocol::MlesJson];
+
+/// Msg of the JSON subprotocol, the message is in base64.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct JsonMsg {
+    uid: String,
+    channel: String,
+    message: String,
+}
+
+/// Frame which the subprotocol does not accept, the session is closed with
+/// the code and reason.
+#[derive(Debug)]
+pub struct Rejected {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl Rejected {
+    fn new(code: u16, reason: &str) -> Self {
+        Rejected {
+            code,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+impl Subprotocol {
+    pub fn name(self) -> &'static str {
+        match self {
+            Subprotocol::MlesWebsocket => "mles-websocket",
+            Subprotocol::MlesJson => "mles-json",
+        }
+    }
+
+    /// Converts a data frame of the client to an encoded Msg, None if the
+    /// frame is ignored by the subprotocol.
+    pub fn from_client(self, message: Message) -> Result<Option<Vec<u8>>, Rejected> {
+        let msg = match self.decode(message)? {
+            Some(msg) => msg,
+            None => return Ok(None),
+        };
+        if Msg::decode(&msg).get_message_len() <= NONCE_LEN {
+            return Err(Rejected::new(
+                CLOSE_INVALID,
+                "message shorter than its nonce",
+            ));
+        }
+        Ok(Some(msg))
+    }
+
+    /// Reads the encoded Msg from the frame by the framing of the subprotocol.
+    fn decode(self, message: Message) -> Result<Option<Vec<u8>>, Rejected> {
+        match self {
+            Subprotocol::MlesWebsocket => {
+                if message.is_binary() {
+                    Ok(Some(message.into_bytes()))
+                } else {
+                    Ok(None)
+                }
+            }
+            Subprotocol::MlesJson => {
+                if message.is_binary() {
+                    return Err(Rejected::new(CLOSE_UNSUPPORTED, "text frames only"));
+                }
+                let text = match message.to_str() {
+                    Ok(text) => text,
+                    Err(_) => return Ok(None),
+                };
+                let json: JsonMsg = serde_json::from_str(text)
+                    .map_err(|_| Rejected::new(CLOSE_INVALID, "malformed JSON message"))?;
+                let message = b64decode(&json.message)
+                    .map_err(|_| Rejected::new(CLOSE_INVALID, "message is not base64"))?;
+                Ok(Some(Msg::new(json.uid, json.channel, message).encode()))
+            }
+        }
+    }
+
+    /// Converts an encoded Msg to a data frame for the client.
+    pub fn to_client(self, msg: Vec<u8>) -> Message {
+        match self {
+            Subprotocol::MlesWebsocket => Message::binary(msg),
+            Subprotocol::MlesJson => {
+                let msg = Msg::decode(&msg);
+                let json = JsonMsg {
+                    uid: msg.get_uid().to_string(),
+                    channel: msg.get_channel().to_string(),
+                    message: b64encode(msg.get_message()),
+                };
+                Message::text(serde_json::to_string(&json).unwrap_or_default())
+            }
+        }
+    }
+}
+
+/// Selects the first subprotocol offered by the client which is registered.
+pub fn negotiate(offered: &str) -> Option<Subprotocol> {
+    offered.split(',').map(|name| name.trim()).find_map(|name| {
+        SUBPROTOCOLS
+            .iter()
+            .find(|protocol| protocol.name() == name)
+            .cloned()
+    })
+}
+
+/// Filter which negotiates the subprotocol from the Sec-WebSocket-Protocol
+/// header and rejects clients offering none of the registered ones.
+pub fn subprotocol() -> impl Filter<Extract = (Subprotocol,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("sec-websocket-protocol").and_then(
+        |offered: Option<String>| match offered.as_ref().and_then(|offered| negotiate(offered)) {
+            Some(protocol) => Ok(protocol),
+            None => Err(warp::reject::not_found()),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_in_client_order() {
+        assert_eq!(
+            negotiate("mles-websocket"),
+            Some(Subprotocol::MlesWebsocket)
+        );
+        assert_eq!(
+            negotiate("mles-json, mles-websocket"),
+            Some(Subprotocol::MlesJson)
+        );
+        assert_eq!(
+            negotiate("mles-websocket,mles-json"),
+            Some(Subprotocol::MlesWebsocket)
+        );
+    }
+
+    #[test]
+    fn negotiate_skips_unknown() {
+        assert_eq!(
+            negotiate("chat, superchat ,mles-json"),
+            Some(Subprotocol::MlesJson)
+        );
+        assert_eq!(negotiate("chat, superchat"), None);
+        assert_eq!(negotiate("MLES-JSON"), None);
+    }
+
+    #[test]
+    fn negotiate_empty() {
+        assert_eq!(negotiate(""), None);
+        assert_eq!(negotiate(" , "), None);
+    }
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len as u8).collect()
+    }
+
+    fn json(message: &[u8]) -> Message {
+        Message::text(format!(
+            r#"{{"uid": "alice", "channel": "a", "message": "{}"}}"#,
+            b64encode(message)
+        ))
+    }
+
+    fn rejected_code(protocol: Subprotocol, message: Message) -> u16 {
+        protocol.from_client(message).unwrap_err().code
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let msg = Subprotocol::MlesJson
+            .from_client(json(&message(20)))
+            .unwrap()
+            .unwrap();
+        let decoded = Msg::decode(&msg);
+        assert_eq!(decoded.get_uid(), "alice");
+        assert_eq!(decoded.get_channel(), "a");
+        assert_eq!(decoded.get_message(), &message(20));
+
+        let frame = Subprotocol::MlesJson.to_client(msg);
+        let text: serde_json::Value = serde_json::from_str(frame.to_str().unwrap()).unwrap();
+        let expected: serde_json::Value =
+            serde_json::from_str(json(&message(20)).to_str().unwrap()).unwrap();
+        assert_eq!(text, expected);
+    }
+
+    #[test]
+    fn invalid_json_closes() {
+        for text in &[
+            "not json",
+            r#"{"uid": "alice", "channel": "a"}"#,
+            r#"{"uid": "alice", "channel": "a", "message": "", "extra": 1}"#,
+            r#"{"uid": "alice", "channel": "a", "message": "not base64!"}"#,
+        ] {
+            assert_eq!(
+                rejected_code(Subprotocol::MlesJson, Message::text(*text)),
+                CLOSE_INVALID
+            );
+        }
+    }
+
+    #[test]
+    fn binary_on_json_closes() {
+        let msg = Msg::new("alice".to_string(), "a".to_string(), message(20)).encode();
+        assert_eq!(
+            rejected_code(Subprotocol::MlesJson, Message::binary(msg)),
+            CLOSE_UNSUPPORTED
+        );
+    }
+
+    #[test]
+    fn websocket_frames() {
+        let msg = Msg::new("alice".to_string(), "a".to_string(), message(20)).encode();
+        assert_eq!(
+            Subprotocol::MlesWebsocket
+                .from_client(Message::binary(msg.clone()))
+                .unwrap(),
+            Some(msg.clone())
+        );
+        assert_eq!(
+            Subprotocol::MlesWebsocket
+                .from_client(Message::text("text"))
+                .unwrap(),
+            None
+        );
+        assert!(Subprotocol::MlesWebsocket.to_client(msg).is_binary());
+    }
+
+    #[test]
+    fn short_message_closes() {
+        for len in &[0, NONCE_LEN - 1, NONCE_LEN] {
+            assert_eq!(
+                rejected_code(Subprotocol::MlesJson, json(&message(*len))),
+                CLOSE_INVALID
+            );
+            let msg = Msg::new("alice".to_string(), "a".to_string(), message(*len)).encode();
+            assert_eq!(
+                rejected_code(Subprotocol::MlesWebsocket, Message::binary(msg)),
+                CLOSE_INVALID
+            );
+        }
+        assert!(Subprotocol::MlesJson
+            .from_client(json(&message(NONCE_LEN + 1)))
+            .is_ok());
+    }
+}
@@ -0,0 +1,310 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ *  Copyright (C) 2020  Mles developers
+ */
+use futures::task::{self, Task};
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::config::OverflowPolicy;
+
+/// Queue with a fixed capacity between two tasks of a session. When it is
+/// full, the policy decides whether the sender waits, the oldest message is
+/// dropped or the send fails as overloaded. Dropped messages are counted.
+pub fn bounded<T>(
+    capacity: usize,
+    policy: OverflowPolicy,
+    drops: Arc<AtomicUsize>,
+) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        capacity,
+        policy,
+        drops,
+        state: Mutex::new(State {
+            items: VecDeque::with_capacity(capacity),
+            receiver: None,
+            blocked: Vec::new(),
+            senders: 1,
+            closed: false,
+        }),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+struct Shared<T> {
+    capacity: usize,
+    policy: OverflowPolicy,
+    drops: Arc<AtomicUsize>,
+    state: Mutex<State<T>>,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    /// Receiver waiting for a message
+    receiver: Option<Task>,
+    /// Senders waiting for room
+    blocked: Vec<Task>,
+    senders: usize,
+    /// The receiver is gone
+    closed: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SendError {
+    /// The queue is full and its policy is close
+    Overloaded,
+    /// The receiver is gone
+    Closed,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SendError::Overloaded => write!(f, "queue is full"),
+            SendError::Closed => write!(f, "queue is closed"),
+        }
+    }
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sink for Sender<T> {
+    type SinkItem = T;
+    type SinkError = SendError;
+
+    fn start_send(&mut self, item: T) -> StartSend<T, SendError> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(SendError::Closed);
+        }
+        if state.items.len() >= self.shared.capacity {
+            match self.shared.policy {
+                OverflowPolicy::Block => {
+                    state.blocked.push(task::current());
+                    return Ok(AsyncSink::NotReady(item));
+                }
+                OverflowPolicy::DropOldest => {
+                    state.items.pop_front();
+                    self.shared.drops.fetch_add(1, Ordering::Relaxed);
+                }
+                OverflowPolicy::Close => {
+                    self.shared.drops.fetch_add(1, Ordering::Relaxed);
+                    return Err(SendError::Overloaded);
+                }
+            }
+        }
+        state.items.push_back(item);
+        if let Some(receiver) = state.receiver.take() {
+            receiver.notify();
+        }
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), SendError> {
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            if let Some(receiver) = state.receiver.take() {
+                receiver.notify();
+            }
+        }
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<T>, ()> {
+        let mut state = self.shared.state.lock().unwrap();
+        if let Some(item) = state.items.pop_front() {
+            for sender in state.blocked.drain(..) {
+                sender.notify();
+            }
+            return Ok(Async::Ready(Some(item)));
+        }
+        if state.senders == 0 {
+            return Ok(Async::Ready(None));
+        }
+        state.receiver = Some(task::current());
+        Ok(Async::NotReady)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.closed = true;
+        state.items.clear();
+        for sender in state.blocked.drain(..) {
+            sender.notify();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::{self, Notify, NotifyHandle};
+    use std::sync::atomic::AtomicBool;
+
+    /// Records whether the task was woken.
+    struct Woken(AtomicBool);
+
+    impl Notify for Woken {
+        fn notify(&self, _id: usize) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    impl Woken {
+        fn new() -> Arc<Self> {
+            Arc::new(Woken(AtomicBool::new(false)))
+        }
+
+        fn get(&self) -> bool {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    fn queue(policy: OverflowPolicy) -> (Sender<u32>, Receiver<u32>, Arc<AtomicUsize>) {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = bounded(2, policy, drops.clone());
+        (tx, rx, drops)
+    }
+
+    #[test]
+    fn block_waits_for_room() {
+        let (tx, rx, drops) = queue(OverflowPolicy::Block);
+        let mut tx = executor::spawn(tx);
+        let mut rx = executor::spawn(rx);
+        let sender = Woken::new();
+        let receiver = Woken::new();
+        let tx_handle = NotifyHandle::from(sender.clone());
+        let rx_handle = NotifyHandle::from(receiver.clone());
+
+        assert_eq!(rx.poll_stream_notify(&rx_handle, 0), Ok(Async::NotReady));
+        assert_eq!(tx.start_send_notify(1, &tx_handle, 0), Ok(AsyncSink::Ready));
+        assert!(receiver.get());
+        assert_eq!(tx.start_send_notify(2, &tx_handle, 0), Ok(AsyncSink::Ready));
+        assert_eq!(
+            tx.start_send_notify(3, &tx_handle, 0),
+            Ok(AsyncSink::NotReady(3))
+        );
+        assert!(!sender.get());
+
+        assert_eq!(
+            rx.poll_stream_notify(&rx_handle, 0),
+            Ok(Async::Ready(Some(1)))
+        );
+        assert!(sender.get());
+        assert_eq!(tx.start_send_notify(3, &tx_handle, 0), Ok(AsyncSink::Ready));
+        assert_eq!(
+            rx.poll_stream_notify(&rx_handle, 0),
+            Ok(Async::Ready(Some(2)))
+        );
+        assert_eq!(
+            rx.poll_stream_notify(&rx_handle, 0),
+            Ok(Async::Ready(Some(3)))
+        );
+        assert_eq!(drops.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn drop_oldest_evicts() {
+        let (tx, rx, drops) = queue(OverflowPolicy::DropOldest);
+        let mut tx = executor::spawn(tx);
+        let mut rx = executor::spawn(rx);
+        let handle = NotifyHandle::from(Woken::new());
+
+        for item in 1..=3 {
+            assert_eq!(tx.start_send_notify(item, &handle, 0), Ok(AsyncSink::Ready));
+        }
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+        assert_eq!(rx.poll_stream_notify(&handle, 0), Ok(Async::Ready(Some(2))));
+        assert_eq!(rx.poll_stream_notify(&handle, 0), Ok(Async::Ready(Some(3))));
+    }
+
+    #[test]
+    fn close_overloads() {
+        let (tx, _rx, drops) = queue(OverflowPolicy::Close);
+        let mut tx = executor::spawn(tx);
+        let handle = NotifyHandle::from(Woken::new());
+
+        assert_eq!(tx.start_send_notify(1, &handle, 0), Ok(AsyncSink::Ready));
+        assert_eq!(tx.start_send_notify(2, &handle, 0), Ok(AsyncSink::Ready));
+        assert_eq!(
+            tx.start_send_notify(3, &handle, 0),
+            Err(SendError::Overloaded)
+        );
+        assert_eq!(drops.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn receiver_dropped() {
+        let (tx, rx, _drops) = queue(OverflowPolicy::Block);
+        let mut tx = executor::spawn(tx);
+        let sender = Woken::new();
+        let handle = NotifyHandle::from(sender.clone());
+
+        assert_eq!(tx.start_send_notify(1, &handle, 0), Ok(AsyncSink::Ready));
+        assert_eq!(tx.start_send_notify(2, &handle, 0), Ok(AsyncSink::Ready));
+        assert_eq!(
+            tx.start_send_notify(3, &handle, 0),
+            Ok(AsyncSink::NotReady(3))
+        );
+        drop(rx);
+        /* The blocked sender wakes up to find the queue closed */
+        assert!(sender.get());
+        assert_eq!(tx.start_send_notify(3, &handle, 0), Err(SendError::Closed));
+    }
+
+    #[test]
+    fn senders_dropped() {
+        let (tx, rx, _drops) = queue(OverflowPolicy::Block);
+        let mut rx = executor::spawn(rx);
+        let receiver = Woken::new();
+        let handle = NotifyHandle::from(receiver.clone());
+        let mut other = executor::spawn(tx.clone());
+
+        assert_eq!(other.start_send_notify(1, &handle, 0), Ok(AsyncSink::Ready));
+        drop(other);
+        assert_eq!(rx.poll_stream_notify(&handle, 0), Ok(Async::Ready(Some(1))));
+        assert_eq!(rx.poll_stream_notify(&handle, 0), Ok(Async::NotReady));
+        drop(tx);
+        /* The waiting receiver wakes up to the end of the stream */
+        assert!(receiver.get());
+        assert_eq!(rx.poll_stream_notify(&handle, 0), Ok(Async::Ready(None)));
+    }
+}
@@ -0,0 +1,338 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ *  Copyright (C) 2020  Mles developers
+ */
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::acme::{self, ChallengeStore};
+use crate::config::{Config, Host};
+use crate::tls::CertResolver;
+use crate::{expire_time, time_to_expiration, ADAY, AMONTH};
+
+const RETRY_MIN: Duration = Duration::from_secs(60);
+const RETRY_MAX: Duration = Duration::from_secs(6 * 60 * 60);
+/// Attempts to obtain a missing certificate before giving up at startup
+const STARTUP_ATTEMPTS: u32 = 4;
+const WARN_DAYS: u64 = 14;
+const CRITICAL_DAYS: u64 = 7;
+
+/// Exponential backoff with jitter for failed certificate requests.
+#[derive(Default)]
+struct Backoff {
+    failures: u32,
+}
+
+impl Backoff {
+    fn failed(&mut self) -> Duration {
+        let delay = RETRY_MIN
+            .checked_mul(1 << self.failures.min(16))
+            .unwrap_or(RETRY_MAX)
+            .min(RETRY_MAX);
+        self.failures += 1;
+        /* Up to a quarter of jitter keeps several proxies from retrying in step */
+        delay + jitter(delay / 4)
+    }
+
+    fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+fn jitter(max: Duration) -> Duration {
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(now) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(now.as_nanos());
+    }
+    let millis = max.as_millis() as u64;
+    if 0 == millis {
+        return Duration::from_secs(0);
+    }
+    Duration::from_millis(hasher.finish() % millis)
+}
+
+/// Severity of the expiry of a certificate whose renewal is failing.
+#[derive(Debug, PartialEq)]
+enum Expiry {
+    Ok,
+    Warning(u64),
+    Critical(u64),
+    Expired,
+}
+
+impl Expiry {
+    /// Classifies the time left until the certificate expires, if it is valid.
+    fn of(remaining: Option<Duration>) -> Self {
+        let remaining = match remaining {
+            Some(remaining) => remaining,
+            None => return Expiry::Expired,
+        };
+        let days = remaining.as_secs() / ADAY.as_secs();
+        if days < CRITICAL_DAYS {
+            Expiry::Critical(days)
+        } else if days < WARN_DAYS {
+            Expiry::Warning(days)
+        } else {
+            Expiry::Ok
+        }
+    }
+}
+
+/// Logs a warning which gets more severe as the expiry of the certificate approaches.
+fn check_expiry(host: &Host) {
+    match Expiry::of(time_to_expiration(&host.pem_name)) {
+        Expiry::Ok => {}
+        Expiry::Warning(days) => println!(
+            "WARNING: certificate for {} expires in {} days and renewal is failing",
+            host.domain, days
+        ),
+        Expiry::Critical(days) => println!(
+            "CRITICAL: certificate for {} expires in {} days and renewal is failing",
+            host.domain, days
+        ),
+        Expiry::Expired => println!(
+            "ERROR: certificate {} for {} is missing or expired",
+            host.pem_name, host.domain
+        ),
+    }
+}
+
+/// Modification times of the certificate and key files of the hosts, for
+/// noticing certificates replaced outside of the proxy.
+pub struct CertWatch {
+    loaded: Vec<Option<SystemTime>>,
+}
+
+impl CertWatch {
+    pub fn new(hosts: &[Host]) -> Self {
+        for host in hosts.iter() {
+            println!(
+                "Watching {} and {} for changes",
+                host.pem_name, host.key_name
+            );
+        }
+        CertWatch {
+            loaded: hosts
+                .iter()
+                .map(|host| cert_modified(&host.pem_name, &host.key_name))
+                .collect(),
+        }
+    }
+
+    /// Records the current files of the host as loaded.
+    fn loaded(&mut self, i: usize, host: &Host) {
+        self.loaded[i] = cert_modified(&host.pem_name, &host.key_name);
+    }
+
+    /// Returns the indexes of the hosts whose files have been replaced with
+    /// a valid certificate since the last check.
+    pub fn changed(&mut self, hosts: &[Host]) -> Vec<usize> {
+        let mut changed = Vec::new();
+        for (i, host) in hosts.iter().enumerate() {
+            let modified = cert_modified(&host.pem_name, &host.key_name);
+            if modified.is_none() || modified == self.loaded[i] {
+                continue;
+            }
+            // Let the writer finish with both files before checking them
+            thread::sleep(Duration::from_secs(1));
+            if time_to_expiration(&host.pem_name).is_some() {
+                println!("Certificate {} changed", host.pem_name);
+                changed.push(i);
+            } else {
+                println!(
+                    "Changed certificate {} is not valid, keeping the current one",
+                    host.pem_name
+                );
+            }
+            self.loaded(i, host);
+        }
+        changed
+    }
+}
+
+fn cert_modified(pem_name: &str, key_name: &str) -> Option<SystemTime> {
+    let pem_time = std::fs::metadata(pem_name).ok()?.modified().ok()?;
+    let key_time = std::fs::metadata(key_name).ok()?.modified().ok()?;
+    Some(std::cmp::max(pem_time, key_time))
+}
+
+/// Returns how long to wait before the next renewal of a valid certificate.
+fn renewal_delay(host: &Host) -> Duration {
+    let expire = expire_time(&host.pem_name);
+    if expire > AMONTH {
+        expire - AMONTH
+    } else {
+        ADAY
+    }
+}
+
+/// Obtains the certificates which do not exist yet and loads them. Exits
+/// if a host still has no valid certificate after a few attempts.
+pub fn obtain_certs(
+    config: &Config,
+    challenges: &ChallengeStore,
+    resolver: &CertResolver,
+    hosts: &[Host],
+) {
+    for host in hosts.iter() {
+        let mut backoff = Backoff::default();
+        for attempt in 1..=STARTUP_ATTEMPTS {
+            match acme::request_cert(config, challenges, host, false) {
+                Err(err) => println!("Cert err: {}", err),
+                Ok(_) => println!("Cert ok!"),
+            }
+            if time_to_expiration(&host.pem_name).is_some() {
+                break;
+            }
+            if attempt < STARTUP_ATTEMPTS {
+                let delay = backoff.failed();
+                println!(
+                    "No certificate for {}, retrying in {:#?}",
+                    host.domain, delay
+                );
+                thread::sleep(delay);
+            }
+        }
+        if time_to_expiration(&host.pem_name).is_none() {
+            println!(
+                "No valid certificate for {} after {} attempts, exiting",
+                host.domain, STARTUP_ATTEMPTS
+            );
+            process::exit(1);
+        }
+        load_cert(resolver, host);
+    }
+}
+
+/// Renews the certificates before they expire, retrying failed renewals with
+/// backoff. Certificates replaced by `cert renew` are reloaded.
+pub fn renew_certs(
+    config: &Config,
+    challenges: &ChallengeStore,
+    resolver: &CertResolver,
+    hosts: &[Host],
+) -> ! {
+    let now = Instant::now();
+    let mut next: Vec<Instant> = hosts.iter().map(|host| now + renewal_delay(host)).collect();
+    let mut backoff: Vec<Backoff> = hosts.iter().map(|_| Backoff::default()).collect();
+    let mut watch = CertWatch::new(hosts);
+    loop {
+        let now = Instant::now();
+        for (i, host) in hosts.iter().enumerate() {
+            if next[i] > now {
+                continue;
+            }
+            match acme::request_cert(config, challenges, host, false) {
+                Ok(_) => {
+                    println!("Cert ok!");
+                    backoff[i].reset();
+                    load_cert(resolver, host);
+                    watch.loaded(i, host);
+                    next[i] = now + renewal_delay(host);
+                }
+                Err(err) => {
+                    let delay = backoff[i].failed();
+                    println!("Cert err: {}, retrying in {:#?}", err, delay);
+                    next[i] = now + delay;
+                    check_expiry(host);
+                }
+            }
+        }
+
+        /* Wake up at least daily to repeat the expiry warnings */
+        let wakeup = next.iter().min().cloned().unwrap_or(now + ADAY);
+        let wakeup = wakeup.min(Instant::now() + ADAY);
+        println!(
+            "Waiting for {:#?} before renewing",
+            wakeup.saturating_duration_since(Instant::now())
+        );
+        let interval = config.tls.reload_duration().max(Duration::from_secs(1));
+        while Instant::now() < wakeup {
+            thread::sleep(interval.min(wakeup.saturating_duration_since(Instant::now())));
+            for i in watch.changed(hosts) {
+                load_cert(resolver, &hosts[i]);
+                backoff[i].reset();
+                next[i] = Instant::now() + renewal_delay(&hosts[i]);
+            }
+        }
+        for (i, host) in hosts.iter().enumerate() {
+            if backoff[i].failures > 0 {
+                check_expiry(host);
+            }
+        }
+    }
+}
+
+pub fn load_cert(resolver: &CertResolver, host: &Host) {
+    // Now we have working keys, let us use them!
+    match resolver.load(host) {
+        Err(err) => {
+            println!("Cert load err: {}", err);
+        }
+        Ok(_) => {
+            println!("Certificate {} loaded", host.pem_name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jitter_stays_below_max() {
+        assert_eq!(jitter(Duration::from_secs(0)), Duration::from_secs(0));
+        let max = Duration::from_secs(15);
+        for _ in 0..100 {
+            assert!(jitter(max) < max);
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut backoff = Backoff::default();
+        let mut base = RETRY_MIN;
+        for _ in 0..20 {
+            let delay = backoff.failed();
+            assert!(delay >= base, "{:?} < {:?}", delay, base);
+            assert!(
+                delay < base + base / 4,
+                "{:?} too long for {:?}",
+                delay,
+                base
+            );
+            base = (base * 2).min(RETRY_MAX);
+        }
+        assert_eq!(base, RETRY_MAX);
+
+        backoff.reset();
+        let delay = backoff.failed();
+        assert!(delay >= RETRY_MIN && delay < RETRY_MIN + RETRY_MIN / 4);
+    }
+
+    #[test]
+    fn expiry_thresholds() {
+        let days = |days: u64| Some(ADAY * days as u32);
+        assert_eq!(Expiry::of(None), Expiry::Expired);
+        assert_eq!(Expiry::of(days(0)), Expiry::Critical(0));
+        assert_eq!(
+            Expiry::of(Some(ADAY * CRITICAL_DAYS as u32 - Duration::from_secs(1))),
+            Expiry::Critical(CRITICAL_DAYS - 1)
+        );
+        assert_eq!(
+            Expiry::of(days(CRITICAL_DAYS)),
+            Expiry::Warning(CRITICAL_DAYS)
+        );
+        assert_eq!(
+            Expiry::of(Some(ADAY * WARN_DAYS as u32 - Duration::from_secs(1))),
+            Expiry::Warning(WARN_DAYS - 1)
+        );
+        assert_eq!(Expiry::of(days(WARN_DAYS)), Expiry::Ok);
+        assert_eq!(Expiry::of(days(90)), Expiry::Ok);
+    }
+}
@@ -0,0 +1,240 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ *  Copyright (C) 2020  Mles developers
+ */
+use blake2::{Blake2s, Digest};
+use regex::Regex;
+use std::convert::TryInto;
+use std::sync::Arc;
+
+use crate::config::{Host, RouteConfig, UpstreamConfig, DEFAULT_SHARD};
+use crate::pool::{self, Pool};
+
+/// Points of each shard on the hash ring, more spread the channels evenly
+const RING_POINTS: usize = 64;
+
+/// Places the channels of an upstream on its shards, so that all clients
+/// of a channel meet on the same Mles servers.
+pub struct Router {
+    routes: Vec<(Matcher, usize)>,
+    /// Sorted points of the shards, a channel goes to the first one at or
+    /// after its own hash
+    ring: Vec<(u64, usize)>,
+    shards: Vec<(String, Arc<Pool>)>,
+}
+
+enum Matcher {
+    Channel(String),
+    Prefix(String),
+    Regex(Regex),
+}
+
+impl Matcher {
+    fn new(route: &RouteConfig) -> Self {
+        if !route.channel.is_empty() {
+            Matcher::Channel(route.channel.clone())
+        } else if !route.prefix.is_empty() {
+            Matcher::Prefix(route.prefix.clone())
+        } else {
+            Matcher::Regex(Regex::new(&route.regex).unwrap()) //already checked
+        }
+    }
+
+    /// Exact names go before prefixes and prefixes before regexes.
+    fn rank(&self) -> u8 {
+        match self {
+            Matcher::Channel(_) => 0,
+            Matcher::Prefix(_) => 1,
+            Matcher::Regex(_) => 2,
+        }
+    }
+
+    fn matches(&self, channel: &str) -> bool {
+        match self {
+            Matcher::Channel(name) => channel == name,
+            Matcher::Prefix(prefix) => channel.starts_with(prefix.as_str()),
+            Matcher::Regex(regex) => regex.is_match(channel),
+        }
+    }
+}
+
+impl Router {
+    pub fn new(upstream: &UpstreamConfig, pools: &mut Vec<Arc<Pool>>) -> Self {
+        let mut shards = vec![(
+            DEFAULT_SHARD.to_string(),
+            pool::shared(&upstream.addresses(), pools),
+        )];
+        for shard in upstream.shards.iter() {
+            shards.push((shard.name.clone(), pool::shared(&shard.addresses(), pools)));
+        }
+        let shard_index = |name: &str| shards.iter().position(|(shard, _)| shard == name);
+        let mut routes: Vec<(Matcher, usize)> = upstream
+            .routes
+            .iter()
+            .map(|route| (Matcher::new(route), shard_index(&route.shard).unwrap())) //already checked
+            .collect();
+        /* Stable, so rules of the same kind keep their order */
+        routes.sort_by_key(|(matcher, _)| matcher.rank());
+
+        /* The points depend on the shard names only, so adding a shard
+         * moves only the channels which land on its points */
+        let mut ring = Vec::with_capacity(shards.len() * RING_POINTS);
+        for (i, (name, _)) in shards.iter().enumerate() {
+            for point in 0..RING_POINTS {
+                ring.push((hash(&format!("{}#{}", name, point)), i));
+            }
+        }
+        ring.sort();
+        Router {
+            routes,
+            ring,
+            shards,
+        }
+    }
+
+    /// Returns the shard name and servers of the channel.
+    pub fn route(&self, channel: &str) -> (&str, &Arc<Pool>) {
+        let route = self
+            .routes
+            .iter()
+            .find(|(matcher, _)| matcher.matches(channel));
+        let index = match route {
+            Some((_, index)) => *index,
+            None => {
+                let hash = hash(channel);
+                let pos = self.ring.partition_point(|(point, _)| *point < hash);
+                self.ring.get(pos).unwrap_or(&self.ring[0]).1
+            }
+        };
+        let (name, pool) = &self.shards[index];
+        (name, pool)
+    }
+}
+
+/// Stable over restarts and builds, so that several proxies place a
+/// channel on the same shard.
+fn hash(name: &str) -> u64 {
+    let mut hasher = Blake2s::new();
+    hasher.update(name);
+    u64::from_be_bytes(hasher.finalize()[..8].try_into().unwrap())
+}
+
+/// Returns the router of each host and all server pools, hosts with the
+/// same servers share their pools.
+pub fn routers(hosts: &[Host]) -> (Vec<Arc<Router>>, Vec<Arc<Pool>>) {
+    let mut pools = Vec::new();
+    let routers = hosts
+        .iter()
+        .map(|host| Arc::new(Router::new(&host.upstream, &mut pools)))
+        .collect();
+    (routers, pools)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ShardConfig;
+
+    fn upstream(shards: &[&str], routes: Vec<RouteConfig>) -> UpstreamConfig {
+        let mut upstream = UpstreamConfig::default();
+        upstream.shards = shards
+            .iter()
+            .enumerate()
+            .map(|(i, name)| ShardConfig {
+                name: name.to_string(),
+                address: format!("127.0.0.1:{}", 8078 + i),
+                fallback_addresses: Vec::new(),
+            })
+            .collect();
+        upstream.routes = routes;
+        upstream
+    }
+
+    fn rule(channel: &str, prefix: &str, regex: &str, shard: &str) -> RouteConfig {
+        RouteConfig {
+            channel: channel.to_string(),
+            prefix: prefix.to_string(),
+            regex: regex.to_string(),
+            shard: shard.to_string(),
+        }
+    }
+
+    fn shard_of(router: &Router, channel: &str) -> String {
+        router.route(channel).0.to_string()
+    }
+
+    #[test]
+    fn precedence() {
+        /* Listed in reverse order of precedence */
+        let routes = vec![
+            rule("", "", "^eu", "regex"),
+            rule("", "eu-", "", "prefix"),
+            rule("eu-main", "", "", "channel"),
+        ];
+        let router = Router::new(
+            &upstream(&["channel", "prefix", "regex"], routes),
+            &mut Vec::new(),
+        );
+        assert_eq!(shard_of(&router, "eu-main"), "channel");
+        assert_eq!(shard_of(&router, "eu-other"), "prefix");
+        assert_eq!(shard_of(&router, "europe"), "regex");
+    }
+
+    #[test]
+    fn first_rule_of_a_kind() {
+        let routes = vec![rule("", "eu-", "", "a"), rule("", "eu-north-", "", "b")];
+        let router = Router::new(&upstream(&["a", "b"], routes), &mut Vec::new());
+        assert_eq!(shard_of(&router, "eu-north-1"), "a");
+    }
+
+    #[test]
+    fn hash_stability() {
+        /* Blake2s, so the placement holds over restarts, builds and proxies */
+        assert_eq!(hash("mles"), 0xea0a_a91e_9492_6573);
+        assert_eq!(hash("default#0"), 0xbccd_f7a8_26f8_8350);
+        let first = Router::new(&upstream(&["a", "b"], Vec::new()), &mut Vec::new());
+        let second = Router::new(&upstream(&["a", "b"], Vec::new()), &mut Vec::new());
+        for i in 0..100 {
+            let channel = format!("channel-{}", i);
+            assert_eq!(shard_of(&first, &channel), shard_of(&second, &channel));
+        }
+    }
+
+    #[test]
+    fn ring_lookup() {
+        let router = Router::new(&upstream(&["a", "b", "c"], Vec::new()), &mut Vec::new());
+        for i in 0..1000 {
+            let channel = format!("channel-{}", i);
+            let hash = hash(&channel);
+            let expected = router
+                .ring
+                .iter()
+                .find(|(point, _)| *point >= hash)
+                .unwrap_or(&router.ring[0])
+                .1;
+            assert_eq!(shard_of(&router, &channel), router.shards[expected].0);
+        }
+    }
+
+    #[test]
+    fn adding_a_shard_moves_few_channels() {
+        let before = Router::new(&upstream(&["a", "b"], Vec::new()), &mut Vec::new());
+        let after = Router::new(&upstream(&["a", "b", "c"], Vec::new()), &mut Vec::new());
+        let channels = 4000;
+        let mut moved = 0;
+        for i in 0..channels {
+            let channel = format!("channel-{}", i);
+            let (old, new) = (shard_of(&before, &channel), shard_of(&after, &channel));
+            if old != new {
+                /* Channels only move to the new shard */
+                assert_eq!(new, "c");
+                moved += 1;
+            }
+        }
+        /* A fourth of the channels are expected to move */
+        assert!(moved > channels / 8, "{} moved", moved);
+        assert!(moved < channels * 2 / 5, "{} moved", moved);
+    }
+}
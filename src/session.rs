@@ -0,0 +1,1219 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ *  Copyright (C) 2020  Mles developers
+ */
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{NewCipher, StreamCipher};
+use aes::{Aes128, Aes128Ctr};
+use base64::{decode as b64decode, encode as b64encode};
+use blake2::{Blake2s, Digest};
+use block_modes::block_padding::Pkcs7;
+use block_modes::{BlockMode, Ecb};
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering};
+use futures::future::{self, Either, Loop};
+use futures::sync::mpsc::{unbounded, UnboundedSender};
+use futures::{stream, Future, Poll, Sink, Stream};
+use mles_utils::{Msg, MsgHdr};
+use std::cmp;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::timer::{Delay, Interval};
+use warp::filters::ws::Message;
+
+use crate::config::{Fanout, KeepaliveConfig, QueueConfig, UpstreamConfig, UpstreamDialect};
+use crate::pool::{Pool, HEALTH_CHECK};
+use crate::protocol::{Subprotocol, CLOSE_BAD_GATEWAY, CLOSE_OVERLOADED, NONCE_LEN};
+use crate::queue::{self, SendError};
+use crate::route::Router;
+use crate::upstream;
+
+type Aes128Ecb = Ecb<Aes128, Pkcs7>;
+
+const AES_NONCELEN: usize = NONCE_LEN;
+/// First delay before reconnecting a lost Mles connection, doubled on
+/// each reconnect until a connection stays up for RECONNECT_STABLE
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(60);
+const RECONNECT_STABLE: Duration = Duration::from_secs(30);
+
+/// Keys derived from the channel name. Names and messages are encrypted
+/// with them towards the Mles server, so each channel has its own.
+struct ChannelKeys {
+    aes: Vec<u8>,
+    ecb: Vec<u8>,
+}
+
+impl ChannelKeys {
+    fn new(channel: &str) -> Self {
+        let mut hasher = Blake2s::new();
+        hasher.update(channel);
+        let hash = hasher.finalize();
+        let mut aes = hash.as_slice().to_vec();
+        aes.truncate(AES_NONCELEN);
+
+        let mut hasher_ecb = Blake2s::new();
+        hasher_ecb.update(hash.as_slice());
+        let mut ecb = hasher_ecb.finalize().as_slice().to_vec();
+        ecb.truncate(AES_NONCELEN);
+        ChannelKeys { aes, ecb }
+    }
+
+    fn encrypt_name(&self, name: &str) -> String {
+        let cipher = Aes128Ecb::new_from_slices(&self.ecb, Default::default()).unwrap();
+        b64encode(&cipher.encrypt_vec(name.as_bytes()))
+    }
+
+    fn decrypt_name(&self, name: &str) -> Option<String> {
+        let name = b64decode(name).ok()?;
+        let cipher = Aes128Ecb::new_from_slices(&self.ecb, Default::default()).unwrap();
+        String::from_utf8(cipher.decrypt_vec(&name).ok()?).ok()
+    }
+
+    /// Applies the AES-CTR keystream to the message after its nonce.
+    fn apply_keystream(&self, msg: &mut Vec<u8>) {
+        let mut aesnonce = Vec::with_capacity(AES_NONCELEN);
+        aesnonce.extend_from_slice(&msg[0..AES_NONCELEN]);
+        let aeskey = GenericArray::from_slice(&self.aes);
+        let nonce = GenericArray::from_slice(&aesnonce);
+        let mut cipher = Aes128Ctr::new(&aeskey, &nonce);
+        cipher.apply_keystream(&mut msg[AES_NONCELEN..]);
+    }
+
+    /// Encrypts a Msg of the client for the Mles server.
+    fn encrypt(&self, msg: Msg) -> Vec<u8> {
+        let uid = self.encrypt_name(msg.get_uid());
+        let channel = self.encrypt_name(msg.get_channel());
+        let mut msg = msg.set_uid(uid).set_channel(channel);
+        self.apply_keystream(msg.get_mut_message());
+        msg.encode()
+    }
+
+    /// Decrypts a Msg of the Mles server for the client, None if it is not valid.
+    fn decrypt(&self, buf: &[u8]) -> Option<Vec<u8>> {
+        let msg = Msg::decode(buf);
+        if !is_valid(&msg) {
+            return None;
+        }
+        let uid = self.decrypt_name(msg.get_uid())?;
+        let channel = self.decrypt_name(msg.get_channel())?;
+        let mut msg = msg.set_uid(uid).set_channel(channel);
+        self.apply_keystream(msg.get_mut_message());
+        Some(msg.encode())
+    }
+}
+
+/// Mles servers of a host and the channel connections which its sessions
+/// share in fan-out mode.
+pub struct Hub {
+    upstream: UpstreamConfig,
+    router: Arc<Router>,
+    shared: Mutex<HashMap<(String, String), Weak<ChannelConn>>>,
+}
+
+impl Hub {
+    pub fn new(upstream: UpstreamConfig, router: Arc<Router>) -> Self {
+        Hub {
+            upstream,
+            router,
+            shared: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Subscribes the session to a connection of the channel. It is set up
+    /// for the session, or shared with other sessions in fan-out mode.
+    fn subscribe(
+        &self,
+        session: &SessionParams,
+        keys: &Arc<ChannelKeys>,
+        uid: &str,
+        channel: &str,
+        subscriber: Subscriber,
+    ) -> Arc<ChannelConn> {
+        let key = match self.upstream.fanout {
+            Fanout::Off => None,
+            Fanout::Channel => Some((channel.to_string(), String::new())),
+            Fanout::Uid => Some((channel.to_string(), uid.to_string())),
+        };
+        let key = match key {
+            Some(key) => key,
+            None => {
+                let drops = session.upstream_drops.clone();
+                let conn = self.open(session, &session.client, drops, keys, uid, channel);
+                conn.subscribers.add(subscriber);
+                return conn;
+            }
+        };
+        let mut shared = self.shared.lock().unwrap();
+        let conn = shared
+            .get(&key)
+            .and_then(Weak::upgrade)
+            .filter(|conn| !conn.ended.load(Ordering::Relaxed));
+        let conn = match conn {
+            Some(conn) => conn,
+            None => {
+                let label = format!("{} (shared)", session.client);
+                let drops = Arc::new(AtomicUsize::new(0));
+                let conn = self.open(session, &label, drops, keys, uid, channel);
+                shared.retain(|_, conn| conn.upgrade().is_some());
+                shared.insert(key, Arc::downgrade(&conn));
+                conn
+            }
+        };
+        conn.subscribers.add(subscriber);
+        conn
+    }
+
+    /// Sets up a connection of the channel on its shard.
+    fn open(
+        &self,
+        session: &SessionParams,
+        label: &str,
+        drops: Arc<AtomicUsize>,
+        keys: &Arc<ChannelKeys>,
+        uid: &str,
+        channel: &str,
+    ) -> Arc<ChannelConn> {
+        let (tcp_sink_tx, tcp_sink_rx) = queue::bounded(
+            session.queues.capacity,
+            session.queues.upstream,
+            drops.clone(),
+        );
+        let subscribers = Arc::new(Subscribers::default());
+        let ended = Arc::new(AtomicBool::new(false));
+        let (shard, pool) = self.router.route(channel);
+        let link = ChannelLink {
+            label: label.to_string(),
+            upstream: self.upstream.clone(),
+            shard: shard.to_string(),
+            pool: pool.clone(),
+            tcp_keepalive: session.tcp_keepalive,
+            keys: keys.clone(),
+            uid: uid.to_string(),
+            channel: channel.to_string(),
+            tcp_sink_rx: SharedReceiver(Arc::new(Mutex::new(tcp_sink_rx))),
+            join: Mutex::new(None),
+            subscribers: subscribers.clone(),
+            ended: ended.clone(),
+        };
+        tokio::spawn(run_channel(Arc::new(link)));
+        Arc::new(ChannelConn {
+            label: label.to_string(),
+            shared: self.upstream.fanout != Fanout::Off,
+            tcp_sink_tx,
+            subscribers,
+            drops,
+            ended,
+        })
+    }
+}
+
+/// What the channel connections of a session are set up with.
+struct SessionParams {
+    client: String,
+    tcp_keepalive: Duration,
+    queues: QueueConfig,
+    upstream_drops: Arc<AtomicUsize>,
+}
+
+/// A channel joined by the client, with its own keys and a subscription to
+/// a Mles connection of the channel.
+struct Channel {
+    keys: Arc<ChannelKeys>,
+    conn: Arc<ChannelConn>,
+    id: usize,
+}
+
+impl Drop for Channel {
+    fn drop(&mut self) {
+        self.conn.subscribers.remove(self.id);
+    }
+}
+
+/// Connection of a channel to the Mles servers and the sessions subscribed
+/// to it. Encrypted messages are queued until the connection is up and
+/// framed when they are sent, as the MsgHdr changes on reconnect. The
+/// connection ends when the last subscription is gone.
+struct ChannelConn {
+    label: String,
+    shared: bool,
+    tcp_sink_tx: queue::Sender<Vec<u8>>,
+    subscribers: Arc<Subscribers>,
+    drops: Arc<AtomicUsize>,
+    /// No session uses the connection anymore, or it could not be set up
+    ended: Arc<AtomicBool>,
+}
+
+impl Drop for ChannelConn {
+    fn drop(&mut self) {
+        self.ended.store(true, Ordering::Relaxed);
+        let drops = self.drops.load(Ordering::Relaxed);
+        /* Drops of a session's own connections are logged with the session */
+        if self.shared && drops > 0 {
+            println!(
+                "Connection of client {} dropped {} messages towards Mles",
+                self.label, drops
+            );
+        }
+    }
+}
+
+static NEXT_SUBSCRIBER: AtomicUsize = AtomicUsize::new(0);
+
+/// Session receiving the messages of a channel connection.
+#[derive(Clone)]
+struct Subscriber {
+    id: usize,
+    ws_tx: queue::Sender<(Arc<ChannelKeys>, Vec<u8>)>,
+    control: Control,
+}
+
+impl Subscriber {
+    fn new(ws_tx: &queue::Sender<(Arc<ChannelKeys>, Vec<u8>)>, control: &Control) -> Self {
+        Subscriber {
+            id: NEXT_SUBSCRIBER.fetch_add(1, Ordering::Relaxed),
+            ws_tx: ws_tx.clone(),
+            control: control.clone(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Subscribers(Mutex<Vec<Subscriber>>);
+
+impl Subscribers {
+    fn add(&self, subscriber: Subscriber) {
+        self.0.lock().unwrap().push(subscriber);
+    }
+
+    fn remove(&self, id: usize) {
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.id != id);
+    }
+
+    /// Queues a message of the channel to the subscribers, except to the
+    /// one which sent it. Each subscriber is queued to with its policy, a
+    /// session which is gone is skipped.
+    fn deliver(
+        &self,
+        keys: &Arc<ChannelKeys>,
+        buf: &[u8],
+        sender: Option<usize>,
+    ) -> impl Future<Item = (), Error = Error> + Send {
+        let sends: Vec<_> = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|subscriber| Some(subscriber.id) != sender)
+            .map(|subscriber| {
+                subscriber
+                    .control
+                    .enqueue(&subscriber.ws_tx, (keys.clone(), buf.to_vec()))
+                    .then(|_| Ok::<_, Error>(()))
+            })
+            .collect();
+        future::join_all(sends).map(|_| ())
+    }
+
+    fn close(&self, code: u16, reason: &str) {
+        for subscriber in self.0.lock().unwrap().iter() {
+            subscriber.control.close(code, reason);
+        }
+    }
+}
+
+/// Queue of a channel, taken over by each of its connections in turn.
+#[derive(Clone)]
+struct SharedReceiver(Arc<Mutex<queue::Receiver<Vec<u8>>>>);
+
+impl Stream for SharedReceiver {
+    type Item = Vec<u8>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, ()> {
+        self.0.lock().unwrap().poll()
+    }
+}
+
+/// What a channel connects with to the Mles server.
+struct ChannelLink {
+    label: String,
+    upstream: UpstreamConfig,
+    shard: String,
+    pool: Arc<Pool>,
+    tcp_keepalive: Duration,
+    keys: Arc<ChannelKeys>,
+    uid: String,
+    channel: String,
+    tcp_sink_rx: SharedReceiver,
+    /// First Msg sent on the channel, which joins it on the legacy dialect
+    join: Mutex<Option<Vec<u8>>>,
+    subscribers: Arc<Subscribers>,
+    ended: Arc<AtomicBool>,
+}
+
+/// How a connection of a channel ended.
+enum Outcome {
+    /// The session is over
+    Closed,
+    /// The connection was up and got lost
+    Lost,
+    /// A server preferred over the connected one is healthy again
+    Moved,
+    /// The connection could not be set up
+    Failed(String),
+}
+
+fn is_valid(msg: &Msg) -> bool {
+    !msg.get_channel().is_empty()
+        && !msg.get_uid().is_empty()
+        && msg.get_message_len() > AES_NONCELEN
+}
+
+/// Returns the MsgHdr key and cid of a channel connection.
+fn msghdr_key(
+    upstream: &UpstreamConfig,
+    laddr: &SocketAddr,
+    keys: &ChannelKeys,
+    uid: &str,
+    channel: &str,
+) -> (u64, u32) {
+    let mut hashkeys = Vec::new();
+    if !upstream.key.is_empty() {
+        hashkeys.push(upstream.key.clone());
+    } else {
+        hashkeys.push(MsgHdr::addr2str(laddr));
+        if !upstream.addr_key.is_empty() {
+            hashkeys.push(upstream.addr_key.clone());
+        }
+    }
+    //create hash for verification
+    hashkeys.push(keys.encrypt_name(uid));
+    hashkeys.push(keys.encrypt_name(channel));
+    let key = MsgHdr::do_hash(&hashkeys);
+    (key, MsgHdr::select_cid(key))
+}
+
+/// Frames sent to the client outside the queues, pings and closes, and
+/// the code the session is closing with, 0 while it is open.
+#[derive(Clone)]
+struct Control {
+    tx: UnboundedSender<Message>,
+    close_code: Arc<AtomicU16>,
+}
+
+impl Control {
+    fn ping(&self) {
+        let _ = self.tx.unbounded_send(Message::ping(Vec::new()));
+    }
+
+    /// Closes the client with the code unless a close was already sent.
+    fn close(&self, code: u16, reason: &str) {
+        let open = self
+            .close_code
+            .compare_exchange(0, code, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok();
+        if open {
+            let _ = self
+                .tx
+                .unbounded_send(Message::close_with(code, reason.to_string()));
+        }
+    }
+
+    fn is_closing(&self) -> bool {
+        self.close_code.load(Ordering::Relaxed) != 0
+    }
+
+    /// Queues the item, waiting for room if the queue blocks. A queue which
+    /// is overloaded closes the client.
+    fn enqueue<T: Send + 'static>(
+        &self,
+        tx: &queue::Sender<T>,
+        item: T,
+    ) -> impl Future<Item = (), Error = Error> + Send {
+        let control = self.clone();
+        tx.clone().send(item).then(move |result| match result {
+            Ok(_) => Ok(()),
+            Err(SendError::Overloaded) => {
+                control.close(CLOSE_OVERLOADED, "queue is full");
+                Ok(())
+            }
+            Err(SendError::Closed) => Err(Error::new(ErrorKind::BrokenPipe, "Broken pipe")),
+        })
+    }
+}
+
+/// Connects the channel to the selected Mles server, joins it and proxies
+/// its messages until the connection or the session ends. A server which
+/// cannot be connected is marked down.
+fn connect_channel(link: Arc<ChannelLink>) -> impl Future<Item = Outcome, Error = ()> + Send {
+    let addr = link.pool.select();
+    upstream::connect(&link.upstream, addr, link.tcp_keepalive).then(move |conn| {
+        let conn = match conn {
+            Ok(conn) => conn,
+            Err(err) => {
+                let err = format!("{}: {}", addr, err);
+                link.pool.mark_down(&addr, &err);
+                return Either::A(future::ok(Outcome::Failed(err)));
+            }
+        };
+        let dialect = link.upstream.dialect;
+        let (key, cid) = msghdr_key(
+            &link.upstream,
+            &conn.local_addr,
+            &link.keys,
+            &link.uid,
+            &link.channel,
+        );
+        println!(
+            "Adding TLS client {} to {} of shard {} with cid {:x}",
+            link.label, addr, link.shard, cid
+        );
+
+        // join the channel again on a reconnect, then flush the queue
+        let join = upstream::join_frame(
+            dialect,
+            key,
+            cid,
+            &link.keys.encrypt_name(&link.uid),
+            &link.keys.encrypt_name(&link.channel),
+            link.join.lock().unwrap().clone(),
+        );
+        let link_inner = link.clone();
+        let frames = link
+            .tcp_sink_rx
+            .clone()
+            .map(move |cbuf| {
+                let mut join = link_inner.join.lock().unwrap();
+                if join.is_none() && dialect == UpstreamDialect::Legacy {
+                    *join = Some(cbuf.clone());
+                }
+                upstream::frame(dialect, key, cid, cbuf)
+            })
+            .map_err(|_| Error::new(ErrorKind::Other, "Sink rx just got an error")); //no errors on RX
+        let write_tcp = conn
+            .sink
+            .send_all(stream::iter_ok::<_, Error>(join))
+            .and_then(move |(tcp_sink, _)| tcp_sink.send_all(frames))
+            .then(|result| match result {
+                /* The queue ends with the session */
+                Ok(_) => Ok::<_, ()>(Outcome::Closed),
+                Err(err) => {
+                    println!("Got error {:#?} to write_tcp!", err);
+                    Ok(Outcome::Lost)
+                }
+            });
+
+        let keys = link.keys.clone();
+        let subscribers = link.subscribers.clone();
+        let write_wstx = conn
+            .stream
+            .for_each(move |buf| {
+                // send to the websockets with the keys of this channel
+                subscribers.deliver(&keys, &buf, None)
+            })
+            .then(|result| {
+                if let Err(err) = result {
+                    println!("Got error {:#?} to write_wstx!", err);
+                }
+                Ok::<_, ()>(Outcome::Lost)
+            });
+
+        // move back once a server before this one is healthy
+        let pool = link.pool.clone();
+        let failback = Interval::new_interval(HEALTH_CHECK)
+            .map_err(|_| ())
+            .take_while(move |_| Ok(pool.select() == addr))
+            .for_each(|_| Ok(()))
+            .then(|_| Ok::<_, ()>(Outcome::Moved));
+
+        Either::B(
+            write_tcp
+                .select(write_wstx)
+                .map(|(outcome, _)| outcome)
+                .map_err(|_| ())
+                .select(failback)
+                .map(|(outcome, _)| outcome)
+                .map_err(|_| ()),
+        )
+    })
+}
+
+/// Keeps the channel connected while the session lasts. A lost connection
+/// is set up again with backoff, with a new MsgHdr key and cid and the join
+/// replayed, while the client stays connected. A server which fails is
+/// failed over to the next healthy one. If no server can be connected at
+/// first, the client is closed.
+fn run_channel(link: Arc<ChannelLink>) -> impl Future<Item = (), Error = ()> + Send {
+    future::loop_fn(
+        (link, false, RECONNECT_MIN),
+        |(link, connected, backoff)| {
+            if link.ended.load(Ordering::Relaxed) {
+                return Either::A(future::ok(Loop::Break(())));
+            }
+            let started = Instant::now();
+            Either::B(connect_channel(link.clone()).and_then(move |outcome| {
+                let connected = match outcome {
+                    Outcome::Failed(_) => connected,
+                    _ => true,
+                };
+                let (delay, next) = match outcome {
+                    Outcome::Closed => return Either::A(future::ok(Loop::Break(()))),
+                    Outcome::Lost => {
+                        /* A server which drops each connection right away is
+                         * not reconnected to in a busy loop */
+                        let backoff = if started.elapsed() >= RECONNECT_STABLE {
+                            RECONNECT_MIN
+                        } else {
+                            backoff
+                        };
+                        println!(
+                            "Lost Mles connection of client {}, reconnecting in {}s",
+                            link.label,
+                            backoff.as_secs()
+                        );
+                        (backoff, cmp::min(backoff * 2, RECONNECT_MAX))
+                    }
+                    Outcome::Moved => {
+                        println!(
+                            "Moving client {} to Mles server {}",
+                            link.label,
+                            link.pool.select()
+                        );
+                        (Duration::from_secs(0), RECONNECT_MIN)
+                    }
+                    Outcome::Failed(err) => {
+                        if link.pool.healthy() {
+                            /* The failed server is marked down. Servers which
+                             * all fail are not failed over in a busy loop */
+                            println!(
+                                "Cannot connect client {} to Mles server {}, failing over in {}s",
+                                link.label,
+                                err,
+                                backoff.as_secs()
+                            );
+                            (backoff, cmp::min(backoff * 2, RECONNECT_MAX))
+                        } else if !connected {
+                            println!(
+                                "Cannot connect client {} to Mles server {}",
+                                link.label, err
+                            );
+                            link.subscribers
+                                .close(CLOSE_BAD_GATEWAY, "Mles server unavailable");
+                            link.ended.store(true, Ordering::Relaxed);
+                            return Either::A(future::ok(Loop::Break(())));
+                        } else {
+                            println!(
+                                "Cannot reconnect client {} to Mles server {}, retry in {}s",
+                                link.label,
+                                err,
+                                backoff.as_secs()
+                            );
+                            (backoff, cmp::min(backoff * 2, RECONNECT_MAX))
+                        }
+                    }
+                };
+                Either::B(
+                    Delay::new(Instant::now() + delay)
+                        .map_err(|_| ())
+                        .map(move |_| Loop::Continue((link, connected, next))),
+                )
+            }))
+        },
+    )
+}
+
+/// Proxies a WebSocket client to the Mles servers. Each channel the client
+/// sends to gets its own keys and a connection to the servers it is routed
+/// to, which is shared with other clients in fan-out mode.
+pub fn run(
+    websocket: warp::ws::WebSocket,
+    client: Option<SocketAddr>,
+    protocol: Subprotocol,
+    hub: &Arc<Hub>,
+    keepalive: &KeepaliveConfig,
+    queues: &QueueConfig,
+) -> impl Future<Item = (), Error = ()> + Send + 'static {
+    let hub = hub.clone();
+    let queues = queues.clone();
+
+    let client = match client {
+        Some(client) => client.to_string(),
+        None => "unknown".to_string(),
+    };
+    println!(
+        "New WebSocket client from {} with {}",
+        client,
+        protocol.name()
+    );
+    let client_inner = client.clone();
+
+    let ping_cntr = Arc::new(AtomicUsize::new(0));
+    let pong_cntr = Arc::new(AtomicUsize::new(0));
+    let upstream_drops = Arc::new(AtomicUsize::new(0));
+    let downstream_drops = Arc::new(AtomicUsize::new(0));
+
+    let (ws_tx, ws_rx) =
+        queue::bounded(queues.capacity, queues.downstream, downstream_drops.clone());
+    let (mles_tx, mles_rx) =
+        queue::bounded(queues.capacity, queues.upstream, upstream_drops.clone());
+    let (combined_tx, combined_rx) =
+        queue::bounded(queues.capacity, queues.downstream, downstream_drops.clone());
+    let (control_tx, control_rx) = unbounded();
+    let control = Control {
+        tx: control_tx,
+        close_code: Arc::new(AtomicU16::new(0)),
+    };
+    let (shutdown_tx, shutdown_rx) = unbounded::<()>();
+
+    let (sink, stream) = websocket.split();
+
+    let when = keepalive.ping_duration();
+    let task = Interval::new_interval(when);
+
+    let ping_cntr_inner = ping_cntr;
+    let pong_cntr_inner = pong_cntr.clone();
+    let control_inner = control.clone();
+    let task = task
+        .for_each(move |_| {
+            let prev_ping_cnt = ping_cntr_inner.fetch_add(1, Ordering::Relaxed);
+            let pong_cnt = pong_cntr_inner.load(Ordering::Relaxed);
+            if pong_cnt + 1 < prev_ping_cnt {
+                println!("Dropping inactive TLS connection..");
+                let _ = shutdown_tx.unbounded_send(());
+            }
+            control_inner.ping();
+            Ok(())
+        })
+        .map_err(|e| panic!("delay errored; err={:?}", e));
+
+    let control_inner = control.clone();
+    let ws_reader = stream
+        .map_err(|err| Error::new(ErrorKind::Other, err.to_string()))
+        .for_each(move |message: Message| {
+            if message.is_pong() {
+                let _ = pong_cntr.fetch_add(1, Ordering::Relaxed);
+                return Either::A(future::ok(()));
+            }
+            if control_inner.is_closing() {
+                /* Wait for the client to answer the close */
+                return Either::A(future::ok(()));
+            }
+            match protocol.from_client(message) {
+                Ok(Some(mles_message)) => {
+                    return Either::B(control_inner.enqueue(&mles_tx, mles_message));
+                }
+                Ok(None) => {}
+                Err(rejected) => {
+                    println!(
+                        "Closing WebSocket client {}: {}",
+                        client_inner, rejected.reason
+                    );
+                    control_inner.close(rejected.code, &rejected.reason);
+                }
+            }
+            Either::A(future::ok(()))
+        });
+
+    let control_inner = control.clone();
+    let tcp_to_ws_writer = ws_rx
+        .map_err(|_| panic!("Ws rx just got an error")) //no errors on RX
+        .for_each(move |(keys, buf): (Arc<ChannelKeys>, Vec<u8>)| {
+            match keys.decrypt(&buf) {
+                Some(dbuf) => {
+                    Either::A(control_inner.enqueue(&combined_tx, protocol.to_client(dbuf)))
+                }
+                /* Just drop handling */
+                None => Either::B(future::ok(())),
+            }
+        });
+
+    let mles_rx = mles_rx.map_err(|_| panic!("Mles rx just got an error")); //no errors on RX
+    let mut channels: HashMap<String, Channel> = HashMap::new();
+    let control_inner = control.clone();
+    let params = SessionParams {
+        client: client.clone(),
+        tcp_keepalive: keepalive.tcp_duration(),
+        queues: queues.clone(),
+        upstream_drops: upstream_drops.clone(),
+    };
+    let send_wsrx = mles_rx.for_each(move |buf: Vec<u8>| {
+        let msg = Msg::decode(buf.as_slice());
+
+        /* Check sanity */
+        if !is_valid(&msg) {
+            return Either::A(future::ok(()));
+        }
+        let channel = msg.get_channel().to_string();
+        let uid = msg.get_uid().to_string();
+
+        if !channels.contains_key(&channel) {
+            let keys = Arc::new(ChannelKeys::new(&channel));
+            let subscriber = Subscriber::new(&ws_tx, &control_inner);
+            let id = subscriber.id;
+            let conn = hub.subscribe(&params, &keys, &uid, &channel, subscriber);
+            channels.insert(channel.clone(), Channel { keys, conn, id });
+        }
+
+        let entry = &channels[&channel];
+        let cbuf = entry.keys.encrypt(msg);
+        /* The Mles server does not echo to the connection, so the other
+         * subscribers of a shared one get the message here */
+        let local = entry
+            .conn
+            .subscribers
+            .deliver(&entry.keys, &cbuf, Some(entry.id));
+        let to_mles = control_inner.enqueue(&entry.conn.tcp_sink_tx, cbuf);
+        Either::B(to_mles.join(local).map(|_| ()))
+    });
+
+    /* Pings and closes are not held back by a full queue */
+    let ws_writer = sink
+        .sink_map_err(|_| ())
+        .send_all(control_rx.select(combined_rx));
+
+    let shutdown = shutdown_rx.into_future().map_err(|_| ());
+
+    let connection = ws_reader
+        .map(|_| ())
+        .map_err(|_| ())
+        .select(ws_writer.map(|_| ()).map_err(|_| ()));
+
+    let conn_with_task = connection
+        .map(|_| ())
+        .map_err(|_| ())
+        .select(task.map(|_| ()).map_err(|_| ()));
+
+    let conn_with_task_and_tcp = conn_with_task
+        .map(|_| ())
+        .map_err(|_| ())
+        .select(tcp_to_ws_writer.map(|_| ()).map_err(|_| ()));
+
+    let conn_with_shutdown = conn_with_task_and_tcp
+        .map(|_| ())
+        .map_err(|_| ())
+        .select(send_wsrx.map(|_| ()).map_err(|_| ()));
+
+    conn_with_shutdown
+        .map(|_| ())
+        .map_err(|_| ())
+        .select(shutdown.map(|_| ()))
+        .then(move |_| {
+            let upstream_drops = upstream_drops.load(Ordering::Relaxed);
+            let downstream_drops = downstream_drops.load(Ordering::Relaxed);
+            if upstream_drops > 0 || downstream_drops > 0 {
+                println!(
+                    "WebSocket client {} dropped {} messages towards Mles and {} towards the client",
+                    client, upstream_drops, downstream_drops
+                );
+            }
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::OverflowPolicy;
+    use futures::sync::mpsc::UnboundedReceiver;
+    use futures::sync::oneshot;
+    use futures::Async;
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use tokio::runtime::current_thread::Runtime;
+    use tokio::timer::Timeout;
+
+    fn msg(uid: &str, channel: &str, text: &str) -> Msg {
+        let mut message = vec![7; AES_NONCELEN];
+        message.extend_from_slice(text.as_bytes());
+        Msg::new(uid.to_string(), channel.to_string(), message)
+    }
+
+    fn hub(addr: SocketAddr, fanout: Fanout) -> Arc<Hub> {
+        let mut upstream = UpstreamConfig::default();
+        upstream.address = addr.to_string();
+        upstream.fanout = fanout;
+        let router = Router::new(&upstream, &mut Vec::new());
+        Arc::new(Hub::new(upstream, Arc::new(router)))
+    }
+
+    fn params() -> SessionParams {
+        SessionParams {
+            client: "test".to_string(),
+            tcp_keepalive: Duration::from_secs(60),
+            queues: QueueConfig::default(),
+            upstream_drops: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// An address which refuses connections.
+    fn refused() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    /// A session with what is queued to its client.
+    struct Client {
+        ws_tx: queue::Sender<(Arc<ChannelKeys>, Vec<u8>)>,
+        ws_rx: queue::Receiver<(Arc<ChannelKeys>, Vec<u8>)>,
+        control: Control,
+        control_rx: UnboundedReceiver<Message>,
+    }
+
+    impl Client {
+        fn new() -> Self {
+            let drops = Arc::new(AtomicUsize::new(0));
+            let (ws_tx, ws_rx) = queue::bounded(16, OverflowPolicy::Block, drops);
+            let (tx, control_rx) = unbounded();
+            let control = Control {
+                tx,
+                close_code: Arc::new(AtomicU16::new(0)),
+            };
+            Client {
+                ws_tx,
+                ws_rx,
+                control,
+                control_rx,
+            }
+        }
+
+        fn subscriber(&self) -> Subscriber {
+            Subscriber::new(&self.ws_tx, &self.control)
+        }
+
+        fn close_code(&self) -> u16 {
+            self.control.close_code.load(Ordering::Relaxed)
+        }
+
+        /// Takes the messages queued to the client.
+        fn queued(&mut self) -> Vec<Vec<u8>> {
+            let ws_rx = &mut self.ws_rx;
+            let mut bufs = Vec::new();
+            future::poll_fn(|| {
+                while let Async::Ready(Some((_, buf))) = ws_rx.poll()? {
+                    bufs.push(buf);
+                }
+                Ok::<_, ()>(Async::Ready(()))
+            })
+            .wait()
+            .unwrap();
+            bufs
+        }
+    }
+
+    /// Reads a Msg framed with its MsgHdr, with the key and cid of the MsgHdr.
+    fn read_frame(stream: &mut TcpStream) -> (u64, u32, Vec<u8>) {
+        let mut hdr = vec![0; MsgHdr::get_hdrkey_len()];
+        stream.read_exact(&mut hdr).unwrap();
+        let msghdr = MsgHdr::decode(hdr);
+        let mut msg = vec![0; msghdr.get_len() as usize];
+        stream.read_exact(&mut msg).unwrap();
+        (msghdr.get_key(), msghdr.get_cid(), msg)
+    }
+
+    #[test]
+    fn channels_have_own_keys() {
+        let a = ChannelKeys::new("a");
+        let b = ChannelKeys::new("b");
+        assert_ne!(a.aes, b.aes);
+        assert_ne!(a.ecb, b.ecb);
+
+        let plain = msg("alice", "a", "hello");
+        let cbuf = a.encrypt(plain.clone());
+        let encrypted = Msg::decode(&cbuf);
+        assert_ne!(encrypted.get_uid(), plain.get_uid());
+        assert_ne!(encrypted.get_channel(), plain.get_channel());
+        assert_ne!(encrypted.get_message(), plain.get_message());
+        assert_ne!(cbuf, b.encrypt(plain.clone()));
+
+        assert_eq!(a.decrypt(&cbuf), Some(plain.encode()));
+        assert_ne!(b.decrypt(&cbuf), Some(plain.encode()));
+    }
+
+    #[test]
+    fn msghdr_key_of_each_channel() {
+        let laddr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let a = ChannelKeys::new("a");
+        let b = ChannelKeys::new("b");
+        let mut upstream = UpstreamConfig::default();
+        for key in &["", "secret"] {
+            upstream.key = key.to_string();
+            let (key_a, cid_a) = msghdr_key(&upstream, &laddr, &a, "alice", "a");
+            let (key_b, cid_b) = msghdr_key(&upstream, &laddr, &b, "alice", "b");
+            assert_ne!(key_a, key_b);
+            assert_eq!(cid_a, MsgHdr::select_cid(key_a));
+            assert_eq!(cid_b, MsgHdr::select_cid(key_b));
+            assert_eq!(
+                msghdr_key(&upstream, &laddr, &a, "alice", "a"),
+                (key_a, cid_a)
+            );
+        }
+    }
+
+    #[test]
+    fn flushes_queued_frames_in_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let hub = hub(listener.local_addr().unwrap(), Fanout::Off);
+        let keys = Arc::new(ChannelKeys::new("a"));
+        let cbufs: Vec<_> = (0..5)
+            .map(|i| keys.encrypt(msg("alice", "a", &i.to_string())))
+            .collect();
+
+        /* The server accepts once the frames are queued */
+        let (tx, rx) = oneshot::channel();
+        let server = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            let (mut stream, peer) = listener.accept().unwrap();
+            let frames: Vec<_> = (0..5).map(|_| read_frame(&mut stream)).collect();
+            let _ = tx.send((peer, frames));
+        });
+
+        let client = Client::new();
+        let mut rt = Runtime::new().unwrap();
+        let queued = cbufs.clone();
+        let conn = rt
+            .block_on(future::lazy(|| {
+                let conn = hub.subscribe(&params(), &keys, "alice", "a", client.subscriber());
+                conn.tcp_sink_tx
+                    .clone()
+                    .send_all(stream::iter_ok(queued))
+                    .map(move |_| conn)
+                    .map_err(|_| ())
+            }))
+            .unwrap();
+        let (peer, frames) = rt.block_on(rx).unwrap();
+        server.join().unwrap();
+
+        let (key, cid) = msghdr_key(&hub.upstream, &peer, &keys, "alice", "a");
+        let expected: Vec<_> = cbufs.into_iter().map(|cbuf| (key, cid, cbuf)).collect();
+        assert_eq!(frames, expected);
+        assert_eq!(client.close_code(), 0);
+        drop(conn);
+    }
+
+    #[test]
+    fn failed_first_connect_closes_client() {
+        let hub = hub(refused(), Fanout::Off);
+        let keys = Arc::new(ChannelKeys::new("a"));
+        let client = Client::new();
+        let mut rt = Runtime::new().unwrap();
+        let conn = rt
+            .block_on(future::lazy(|| {
+                Ok::<_, ()>(hub.subscribe(&params(), &keys, "alice", "a", client.subscriber()))
+            }))
+            .unwrap();
+
+        let Client {
+            control,
+            control_rx,
+            ..
+        } = client;
+        let closed = Timeout::new(control_rx.into_future(), Duration::from_secs(5));
+        let (message, _) = rt.block_on(closed).ok().unwrap();
+        assert!(message.unwrap().is_close());
+        assert_eq!(
+            control.close_code.load(Ordering::Relaxed),
+            CLOSE_BAD_GATEWAY
+        );
+        assert!(conn.ended.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn replays_legacy_join_on_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let hub = hub(listener.local_addr().unwrap(), Fanout::Off);
+        let keys = Arc::new(ChannelKeys::new("a"));
+        let cbufs: Vec<_> = (0..2)
+            .map(|i| keys.encrypt(msg("alice", "a", &i.to_string())))
+            .collect();
+
+        /* The first connection is lost after the frames */
+        let (tx, rx) = oneshot::channel();
+        let server = thread::spawn(move || {
+            let (mut stream, first_peer) = listener.accept().unwrap();
+            let first: Vec<_> = (0..2).map(|_| read_frame(&mut stream)).collect();
+            drop(stream);
+            let (mut stream, peer) = listener.accept().unwrap();
+            let join = read_frame(&mut stream);
+            let _ = tx.send(((first_peer, first), (peer, join)));
+        });
+
+        let client = Client::new();
+        let mut rt = Runtime::new().unwrap();
+        let queued = cbufs.clone();
+        let conn = rt
+            .block_on(future::lazy(|| {
+                let conn = hub.subscribe(&params(), &keys, "alice", "a", client.subscriber());
+                conn.tcp_sink_tx
+                    .clone()
+                    .send_all(stream::iter_ok(queued))
+                    .map(move |_| conn)
+                    .map_err(|_| ())
+            }))
+            .unwrap();
+        let received = Timeout::new(rx.map_err(|_| ()), Duration::from_secs(10));
+        let ((first_peer, first), (peer, join)) = rt.block_on(received).ok().unwrap();
+        server.join().unwrap();
+
+        let (first_key, first_cid) = msghdr_key(&hub.upstream, &first_peer, &keys, "alice", "a");
+        assert_eq!(first[0], (first_key, first_cid, cbufs[0].clone()));
+        let (key, cid) = msghdr_key(&hub.upstream, &peer, &keys, "alice", "a");
+        assert_ne!(key, first_key);
+        assert_eq!(join, (key, cid, cbufs[0].clone()));
+        assert_eq!(client.close_code(), 0);
+        drop(conn);
+    }
+
+    #[test]
+    fn shares_a_connection_per_key() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let keys = Arc::new(ChannelKeys::new("a"));
+        let client = Client::new();
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(future::lazy(|| {
+            let params = params();
+            let subscribe = |hub: &Hub, uid: &str, channel: &str| {
+                hub.subscribe(&params, &keys, uid, channel, client.subscriber())
+            };
+
+            let by_channel = hub(addr, Fanout::Channel);
+            let alice = subscribe(&by_channel, "alice", "a");
+            assert!(Arc::ptr_eq(&alice, &subscribe(&by_channel, "bob", "a")));
+            assert!(!Arc::ptr_eq(&alice, &subscribe(&by_channel, "alice", "b")));
+            assert_eq!(alice.subscribers.0.lock().unwrap().len(), 2);
+
+            let by_uid = hub(addr, Fanout::Uid);
+            let alice = subscribe(&by_uid, "alice", "a");
+            assert!(Arc::ptr_eq(&alice, &subscribe(&by_uid, "alice", "a")));
+            assert!(!Arc::ptr_eq(&alice, &subscribe(&by_uid, "bob", "a")));
+
+            let off = hub(addr, Fanout::Off);
+            let alice = subscribe(&off, "alice", "a");
+            assert!(!Arc::ptr_eq(&alice, &subscribe(&off, "alice", "a")));
+            Ok::<_, ()>(())
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn delivers_locally_except_to_sender() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let hub = hub(listener.local_addr().unwrap(), Fanout::Channel);
+        let keys = Arc::new(ChannelKeys::new("a"));
+        let mut clients: Vec<_> = (0..3).map(|_| Client::new()).collect();
+        let mut rt = Runtime::new().unwrap();
+        let (conn, sender) = rt
+            .block_on(future::lazy(|| {
+                let mut conn = None;
+                let mut ids = Vec::new();
+                for client in clients.iter() {
+                    let subscriber = client.subscriber();
+                    ids.push(subscriber.id);
+                    conn = Some(hub.subscribe(&params(), &keys, "alice", "a", subscriber));
+                }
+                Ok::<_, ()>((conn.unwrap(), ids[0]))
+            }))
+            .unwrap();
+
+        let cbuf = keys.encrypt(msg("alice", "a", "hello"));
+        rt.block_on(conn.subscribers.deliver(&keys, &cbuf, Some(sender)))
+            .unwrap();
+        assert!(clients[0].queued().is_empty());
+        assert_eq!(clients[1].queued(), vec![cbuf.clone()]);
+        assert_eq!(clients[2].queued(), vec![cbuf]);
+    }
+
+    #[test]
+    fn last_subscriber_releases_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let hub = hub(listener.local_addr().unwrap(), Fanout::Channel);
+        let keys = Arc::new(ChannelKeys::new("a"));
+        let (accepted_tx, accepted_rx) = oneshot::channel();
+        let (closed_tx, closed_rx) = oneshot::channel();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = accepted_tx.send(());
+            let _ = stream.read_to_end(&mut Vec::new());
+            let _ = closed_tx.send(());
+        });
+
+        let clients: Vec<_> = (0..2).map(|_| Client::new()).collect();
+        let mut rt = Runtime::new().unwrap();
+        let mut channels = rt
+            .block_on(future::lazy(|| {
+                let channels: Vec<_> = clients
+                    .iter()
+                    .map(|client| {
+                        let subscriber = client.subscriber();
+                        let id = subscriber.id;
+                        let conn = hub.subscribe(&params(), &keys, "alice", "a", subscriber);
+                        Channel {
+                            keys: keys.clone(),
+                            conn,
+                            id,
+                        }
+                    })
+                    .collect();
+                Ok::<_, ()>(channels)
+            }))
+            .unwrap();
+        let ended = channels[0].conn.ended.clone();
+        let shared = || {
+            let key = ("a".to_string(), String::new());
+            hub.shared.lock().unwrap()[&key].upgrade()
+        };
+        rt.block_on(accepted_rx).unwrap();
+
+        channels.pop();
+        let conn = shared().unwrap();
+        assert_eq!(conn.subscribers.0.lock().unwrap().len(), 1);
+        drop(conn);
+        assert!(!ended.load(Ordering::Relaxed));
+
+        channels.pop();
+        assert!(shared().is_none());
+        assert!(ended.load(Ordering::Relaxed));
+        let closed = Timeout::new(closed_rx, Duration::from_secs(5));
+        rt.block_on(closed).ok().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn failure_closes_all_subscribers() {
+        let hub = hub(refused(), Fanout::Channel);
+        let keys = Arc::new(ChannelKeys::new("a"));
+        let clients: Vec<_> = (0..2).map(|_| Client::new()).collect();
+        let mut rt = Runtime::new().unwrap();
+        let conn = rt
+            .block_on(future::lazy(|| {
+                let mut conn = None;
+                for (client, uid) in clients.iter().zip(&["alice", "bob"]) {
+                    conn = Some(hub.subscribe(&params(), &keys, uid, "a", client.subscriber()));
+                }
+                Ok::<_, ()>(conn.unwrap())
+            }))
+            .unwrap();
+
+        for client in clients {
+            let closed = Timeout::new(client.control_rx.into_future(), Duration::from_secs(5));
+            let (message, _) = rt.block_on(closed).ok().unwrap();
+            assert!(message.unwrap().is_close());
+            assert_eq!(
+                client.control.close_code.load(Ordering::Relaxed),
+                CLOSE_BAD_GATEWAY
+            );
+        }
+        assert!(conn.ended.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,294 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ *  Copyright (C) 2020  Mles developers
+ */
+use acme_lib::persist::{Persist, PersistKey, PersistKind};
+use openssl::hash::MessageDigest;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Error, ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::config::StorageConfig;
+
+/// Marks a file encrypted with the passphrase
+const MAGIC: &[u8] = b"MLESENC1";
+const SALT_LEN: usize = 16;
+const IV_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const PBKDF2_ITERATIONS: usize = 100_000;
+const PRIVATE_MODE: u32 = 0o600;
+const DIR_MODE: u32 = 0o700;
+
+/// State directory for ACME accounts, certificates and private keys.
+///
+/// Private keys are written with mode 0600 and encrypted with AES-256-GCM
+/// if a passphrase is configured. Unencrypted keys are still readable, so
+/// existing keys keep working after a passphrase is taken into use.
+#[derive(Clone)]
+pub struct Storage {
+    dir: PathBuf,
+    passphrase: Option<String>,
+}
+
+impl Storage {
+    pub fn new(config: &StorageConfig) -> Self {
+        let passphrase = if config.passphrase.is_empty() {
+            None
+        } else {
+            Some(config.passphrase.clone())
+        };
+        Storage {
+            dir: PathBuf::from(&config.dir),
+            passphrase,
+        }
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// Creates the state directory accessible only by the owner.
+    pub fn create_dir(&self) -> io::Result<()> {
+        if self.dir.is_dir() {
+            return Ok(());
+        }
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(DIR_MODE)
+            .create(&self.dir)
+    }
+
+    pub fn write_private<P: AsRef<Path>>(&self, path: P, data: &[u8]) -> io::Result<()> {
+        let data = match &self.passphrase {
+            Some(passphrase) => encrypt(passphrase, data)?,
+            None => data.to_vec(),
+        };
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(PRIVATE_MODE)
+            .open(&path)?;
+        /* The mode applies only to new files */
+        file.set_permissions(fs::Permissions::from_mode(PRIVATE_MODE))?;
+        file.write_all(&data)
+    }
+
+    pub fn read_private<P: AsRef<Path>>(&self, path: P) -> io::Result<Vec<u8>> {
+        let data = fs::read(&path)?;
+        if !data.starts_with(MAGIC) {
+            return Ok(data);
+        }
+        match &self.passphrase {
+            Some(passphrase) => decrypt(passphrase, &data),
+            None => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} is encrypted, passphrase missing",
+                    path.as_ref().display()
+                ),
+            )),
+        }
+    }
+
+    /// Restricts the permissions of an existing private key and encrypts it
+    /// if a passphrase is configured.
+    pub fn protect_private<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        if !path.as_ref().is_file() {
+            return Ok(());
+        }
+        let data = self.read_private(&path)?;
+        self.write_private(&path, &data)
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> io::Result<[u8; KEY_LEN]> {
+    let mut key = [0u8; KEY_LEN];
+    openssl::pkcs5::pbkdf2_hmac(
+        passphrase.as_bytes(),
+        salt,
+        PBKDF2_ITERATIONS,
+        MessageDigest::sha256(),
+        &mut key,
+    )
+    .map_err(other_err)?;
+    Ok(key)
+}
+
+/// Returns MAGIC | salt | iv | tag | ciphertext
+fn encrypt(passphrase: &str, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut salt = [0u8; SALT_LEN];
+    let mut iv = [0u8; IV_LEN];
+    openssl::rand::rand_bytes(&mut salt).map_err(other_err)?;
+    openssl::rand::rand_bytes(&mut iv).map_err(other_err)?;
+    let key = derive_key(passphrase, &salt)?;
+    let mut tag = [0u8; TAG_LEN];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        &key,
+        Some(&iv),
+        MAGIC,
+        data,
+        &mut tag,
+    )
+    .map_err(other_err)?;
+    let mut out = Vec::with_capacity(MAGIC.len() + SALT_LEN + IV_LEN + TAG_LEN + ciphertext.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&salt);
+    out.extend_from_slice(&iv);
+    out.extend_from_slice(&tag);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+fn decrypt(passphrase: &str, data: &[u8]) -> io::Result<Vec<u8>> {
+    let hdr_len = MAGIC.len() + SALT_LEN + IV_LEN + TAG_LEN;
+    if data.len() < hdr_len {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Truncated encrypted file",
+        ));
+    }
+    let salt = &data[MAGIC.len()..MAGIC.len() + SALT_LEN];
+    let iv = &data[MAGIC.len() + SALT_LEN..MAGIC.len() + SALT_LEN + IV_LEN];
+    let tag = &data[hdr_len - TAG_LEN..hdr_len];
+    let key = derive_key(passphrase, salt)?;
+    decrypt_aead(
+        Cipher::aes_256_gcm(),
+        &key,
+        Some(iv),
+        MAGIC,
+        &data[hdr_len..],
+        tag,
+    )
+    .map_err(|_| Error::new(ErrorKind::InvalidData, "Wrong passphrase or corrupted file"))
+}
+
+fn other_err<E: std::fmt::Display>(err: E) -> Error {
+    Error::new(ErrorKind::Other, err.to_string())
+}
+
+/// acme-lib persistence in the state directory.
+#[derive(Clone)]
+pub struct StatePersist {
+    storage: Storage,
+}
+
+impl StatePersist {
+    pub fn new(storage: &Storage) -> Self {
+        StatePersist {
+            storage: storage.clone(),
+        }
+    }
+
+    fn path(&self, key: &PersistKey) -> PathBuf {
+        let kind = match key.kind {
+            PersistKind::Certificate => "crt",
+            PersistKind::PrivateKey => "key",
+            PersistKind::AccountPrivateKey => "account_key",
+        };
+        self.storage
+            .path(&format!("{}_{}_{}", key.realm, key.key, kind))
+    }
+}
+
+impl Persist for StatePersist {
+    fn put(&self, key: &PersistKey, value: &[u8]) -> acme_lib::Result<()> {
+        let path = self.path(key);
+        match key.kind {
+            PersistKind::Certificate => fs::write(path, value)?,
+            _ => self.storage.write_private(path, value)?,
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &PersistKey) -> acme_lib::Result<Option<Vec<u8>>> {
+        let path = self.path(key);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        Ok(Some(self.storage.read_private(path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(dir: &Path, passphrase: &str) -> Storage {
+        Storage::new(&StorageConfig {
+            dir: dir.join("state").to_string_lossy().into_owned(),
+            passphrase: passphrase.to_string(),
+        })
+    }
+
+    fn mode<P: AsRef<Path>>(path: P) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let storage = state(tmp.path(), "secret");
+        storage.create_dir().unwrap();
+        let path = storage.path("host.key");
+        storage.write_private(&path, b"private key").unwrap();
+
+        let raw = fs::read(&path).unwrap();
+        assert!(raw.starts_with(MAGIC));
+        assert!(!raw.windows(11).any(|window| window == b"private key"));
+        assert_eq!(storage.read_private(&path).unwrap(), b"private key");
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let tmp = tempfile::tempdir().unwrap();
+        let storage = state(tmp.path(), "secret");
+        storage.create_dir().unwrap();
+        let path = storage.path("host.key");
+        storage.write_private(&path, b"private key").unwrap();
+
+        for passphrase in &["other", ""] {
+            let err = state(tmp.path(), passphrase)
+                .read_private(&path)
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn reads_unencrypted_key() {
+        let tmp = tempfile::tempdir().unwrap();
+        let storage = state(tmp.path(), "secret");
+        storage.create_dir().unwrap();
+        let path = storage.path("host.key");
+        fs::write(&path, b"legacy key").unwrap();
+        assert_eq!(storage.read_private(&path).unwrap(), b"legacy key");
+
+        storage.protect_private(&path).unwrap();
+        assert!(fs::read(&path).unwrap().starts_with(MAGIC));
+        assert_eq!(storage.read_private(&path).unwrap(), b"legacy key");
+    }
+
+    #[test]
+    fn private_modes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let storage = state(tmp.path(), "");
+        storage.create_dir().unwrap();
+        assert_eq!(mode(storage.path("")), DIR_MODE);
+
+        let path = storage.path("host.key");
+        storage.write_private(&path, b"private key").unwrap();
+        assert_eq!(mode(&path), PRIVATE_MODE);
+
+        /* An existing key readable by others is restricted */
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        storage.protect_private(&path).unwrap();
+        assert_eq!(mode(&path), PRIVATE_MODE);
+    }
+}
@@ -0,0 +1,547 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ *  Copyright (C) 2020  Mles developers
+ */
+use futures::future::Either;
+use futures::{try_ready, Async, Future, Poll};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Error, ErrorKind};
+use std::sync::{Arc, RwLock};
+use tokio::net::TcpStream;
+use tokio_io::AsyncRead;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    sign, Certificate, NoClientAuth, ProtocolVersion, ResolvesServerCert, ServerConfig,
+    SignatureScheme, SupportedCipherSuite, ALL_CIPHERSUITES,
+};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::{webpki, TlsAcceptor};
+
+use crate::config::{Host, TlsConfig};
+use crate::net::{ClientConn, PrefixedStream};
+use crate::storage::Storage;
+
+/// ALPN protocol of the ACME TLS-ALPN-01 challenge (RFC 8737)
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+const TLS_RECORD_HDRLEN: usize = 5;
+const TLS_RECORD_MAXLEN: usize = 16384;
+const TLS_HANDSHAKE: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+const TLS_EXT_ALPN: u16 = 16;
+
+/// Certificate resolver whose certificates can be swapped while the
+/// TLS listeners keep running. New handshakes use the new certificate,
+/// established connections are not affected.
+///
+/// The certificate is selected by SNI, the default host is used for
+/// unknown names and clients without SNI.
+///
+/// OCSP responses are kept with the certificate they were fetched for, so
+/// a replaced certificate is not stapled with the response of the old one.
+pub struct CertResolver {
+    hosts: Vec<Host>,
+    storage: Storage,
+    keys: RwLock<HashMap<String, sign::CertifiedKey>>,
+    staples: RwLock<HashMap<String, (Certificate, Vec<u8>)>>,
+}
+
+impl CertResolver {
+    pub fn new(hosts: Vec<Host>, storage: Storage) -> Self {
+        CertResolver {
+            hosts,
+            storage,
+            keys: RwLock::new(HashMap::new()),
+            staples: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Loads the certificate chain and private key of the host and takes them into use.
+    pub fn load(&self, host: &Host) -> Result<(), String> {
+        let key = load_certified_key(&self.storage, &host.pem_name, &host.key_name)?;
+        self.keys.write().unwrap().insert(host.domain.clone(), key);
+        Ok(())
+    }
+
+    /// Returns the certificate chain in use for the domain.
+    pub fn chain(&self, domain: &str) -> Option<Vec<Certificate>> {
+        let keys = self.keys.read().unwrap();
+        keys.get(domain).map(|key| key.cert.clone())
+    }
+
+    /// Staples the OCSP response to the handshakes which use the certificate,
+    /// without a response stapling is stopped.
+    pub fn set_ocsp(&self, domain: &str, cert: Certificate, response: Option<Vec<u8>>) {
+        let mut staples = self.staples.write().unwrap();
+        match response {
+            Some(response) => staples.insert(domain.to_string(), (cert, response)),
+            None => staples.remove(domain),
+        };
+    }
+
+    /// Returns the stapled OCSP response of the domain.
+    pub fn ocsp(&self, domain: &str) -> Option<Vec<u8>> {
+        let staples = self.staples.read().unwrap();
+        staples.get(domain).map(|(_, response)| response.clone())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(
+        &self,
+        server_name: Option<webpki::DNSNameRef>,
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<sign::CertifiedKey> {
+        let keys = self.keys.read().unwrap();
+        let name: Option<&str> = server_name.map(|name| name.into());
+        let host = name.and_then(|name| self.hosts.iter().find(|host| host.matches(name)));
+        let host = host.or_else(|| self.hosts.first())?;
+        let mut key = keys.get(&host.domain).cloned()?;
+        if let Some((cert, response)) = self.staples.read().unwrap().get(&host.domain) {
+            if key.cert.first() == Some(cert) {
+                key.ocsp = Some(response.clone());
+            }
+        }
+        Some(key)
+    }
+}
+
+fn load_certified_key(
+    storage: &Storage,
+    pem_name: &str,
+    key_name: &str,
+) -> Result<sign::CertifiedKey, String> {
+    let file = File::open(pem_name).map_err(|err| format!("{}: {}", pem_name, err))?;
+    let certs = pemfile::certs(&mut BufReader::new(file))
+        .map_err(|_| format!("{}: invalid certificate", pem_name))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates", pem_name));
+    }
+
+    let pem = storage
+        .read_private(key_name)
+        .map_err(|err| format!("{}: {}", key_name, err))?;
+    let mut keys = pemfile::pkcs8_private_keys(&mut &pem[..])
+        .map_err(|_| format!("{}: invalid private key", key_name))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut &pem[..])
+            .map_err(|_| format!("{}: invalid private key", key_name))?;
+    }
+    let key = match keys.first() {
+        Some(key) => key,
+        None => return Err(format!("{}: no private key", key_name)),
+    };
+    let key = sign::any_supported_type(key)
+        .map_err(|_| format!("{}: unsupported private key", key_name))?;
+    Ok(sign::CertifiedKey::new(certs, Arc::new(key)))
+}
+
+/// Returns the cipher suite with the given rustls name, e.g. "TLS13_AES_256_GCM_SHA384".
+pub fn cipher_suite(name: &str) -> Option<&'static SupportedCipherSuite> {
+    ALL_CIPHERSUITES
+        .iter()
+        .find(|suite| format!("{:?}", suite.suite) == name)
+        .cloned()
+}
+
+/// Server configuration with the TLS version, cipher suite and ALPN policy.
+pub fn server_config(resolver: Arc<CertResolver>, policy: &TlsConfig) -> Arc<ServerConfig> {
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = resolver;
+    config.versions = match policy.min_version.as_str() {
+        "1.3" => vec![ProtocolVersion::TLSv1_3],
+        _ => vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+    };
+    if !policy.cipher_suites.is_empty() {
+        config.ciphersuites = policy
+            .cipher_suites
+            .iter()
+            .filter_map(|name| cipher_suite(name))
+            .collect();
+        /* The configured order is the order of preference */
+        config.ignore_client_order = true;
+    }
+    let protocols: Vec<Vec<u8>> = policy
+        .alpn_protocols
+        .iter()
+        .map(|protocol| protocol.as_bytes().to_vec())
+        .collect();
+    config.set_protocols(&protocols);
+    Arc::new(config)
+}
+
+/// Server configuration which answers TLS-ALPN-01 challenges with the
+/// challenge certificates of the resolver.
+pub fn acme_server_config(resolver: Arc<dyn ResolvesServerCert>) -> Arc<ServerConfig> {
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = resolver;
+    config.set_protocols(&[ACME_TLS_ALPN.to_vec()]);
+    Arc::new(config)
+}
+
+/// Creates the self-signed TLS-ALPN-01 challenge certificate for the domain
+/// which carries the SHA-256 digest of the key authorization. Returns the
+/// certificate and the private key in PEM format.
+pub fn acme_challenge_cert(domain: &str, proof: &[u8]) -> Result<String, String> {
+    let mut params = rcgen::CertificateParams::new(vec![domain.to_string()]);
+    params.custom_extensions = vec![rcgen::CustomExtension::new_acme_identifier(proof)];
+    let cert = rcgen::Certificate::from_params(params).map_err(|err| err.to_string())?;
+    let pem = cert.serialize_pem().map_err(|err| err.to_string())?;
+    Ok(pem + &cert.serialize_private_key_pem())
+}
+
+/// Parses a certificate and its PKCS#8 private key from the same PEM buffer.
+pub fn parse_certified_key(pem: &[u8]) -> Result<sign::CertifiedKey, String> {
+    let certs = pemfile::certs(&mut &pem[..]).map_err(|_| "invalid certificate".to_string())?;
+    let keys = pemfile::pkcs8_private_keys(&mut &pem[..])
+        .map_err(|_| "invalid private key".to_string())?;
+    let key = match (certs.is_empty(), keys.first()) {
+        (false, Some(key)) => key,
+        _ => return Err("no certificate or private key".to_string()),
+    };
+    let key = sign::any_supported_type(key).map_err(|_| "unsupported private key".to_string())?;
+    Ok(sign::CertifiedKey::new(certs, Arc::new(key)))
+}
+
+/// TLS handshake of the accepted connections using the given server
+/// configuration.
+///
+/// If an ACME configuration is given, connections offering only the
+/// acme-tls/1 protocol are handed to it and closed after the handshake.
+#[derive(Clone)]
+pub struct TlsHandshake {
+    acceptor: TlsAcceptor,
+    acme_acceptor: Option<TlsAcceptor>,
+}
+
+impl TlsHandshake {
+    pub fn new(config: Arc<ServerConfig>, acme_config: Option<Arc<ServerConfig>>) -> Self {
+        TlsHandshake {
+            acceptor: TlsAcceptor::from(config),
+            acme_acceptor: acme_config.map(TlsAcceptor::from),
+        }
+    }
+
+    /// Returns the connection with the peer address, or none after an ACME challenge.
+    pub fn accept(
+        &self,
+        stream: TcpStream,
+    ) -> impl Future<
+        Item = Option<ClientConn<TlsStream<PrefixedStream<TcpStream>>>>,
+        Error = io::Error,
+    > + Send {
+        let peer = stream.peer_addr().ok();
+        let acceptor = self.acceptor.clone();
+        let acme_acceptor = self.acme_acceptor.clone();
+        PeekClientHello::new(stream).and_then(move |(hello, stream)| {
+            let acme = acme_challenge_offered(&hello);
+            let stream = PrefixedStream::new(hello, stream);
+            match acme_acceptor {
+                Some(acme_acceptor) if acme => {
+                    Either::A(acme_acceptor.accept(stream).map(move |_| {
+                        println!("Answered TLS-ALPN-01 challenge from {:?}", peer);
+                        None
+                    }))
+                }
+                _ => Either::B(
+                    acceptor
+                        .accept(stream)
+                        .map(move |stream| Some(ClientConn::new(stream, peer))),
+                ),
+            }
+        })
+    }
+}
+
+/// Reads the first TLS record of the connection without consuming it, so
+/// that the acceptor can be chosen by the offered ALPN protocols.
+struct PeekClientHello<S> {
+    stream: Option<S>,
+    buf: Vec<u8>,
+}
+
+impl<S: AsyncRead> PeekClientHello<S> {
+    fn new(stream: S) -> Self {
+        PeekClientHello {
+            stream: Some(stream),
+            buf: Vec::new(),
+        }
+    }
+
+    fn complete(&self) -> bool {
+        if self.buf.len() < TLS_RECORD_HDRLEN {
+            return false;
+        }
+        /* Not TLS, let the acceptor fail on it */
+        if self.buf[0] != TLS_HANDSHAKE {
+            return true;
+        }
+        let len = u16::from_be_bytes([self.buf[3], self.buf[4]]) as usize;
+        self.buf.len() >= TLS_RECORD_HDRLEN + len.min(TLS_RECORD_MAXLEN)
+    }
+}
+
+impl<S: AsyncRead> Future for PeekClientHello<S> {
+    type Item = (Vec<u8>, S);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, io::Error> {
+        while !self.complete() {
+            let mut tmp = [0u8; 1024];
+            let stream = self.stream.as_mut().unwrap();
+            let len = try_ready!(stream.poll_read(&mut tmp));
+            if 0 == len {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "Closed in handshake"));
+            }
+            self.buf.extend_from_slice(&tmp[..len]);
+        }
+        let buf = std::mem::replace(&mut self.buf, Vec::new());
+        Ok(Async::Ready((buf, self.stream.take().unwrap())))
+    }
+}
+
+/// Returns true if the ClientHello in the record offers only the
+/// acme-tls/1 protocol, as required from the validation servers.
+fn acme_challenge_offered(record: &[u8]) -> bool {
+    client_hello_alpn(record).map_or(false, |protocols| {
+        !protocols.is_empty() && protocols.iter().all(|proto| proto == &ACME_TLS_ALPN)
+    })
+}
+
+/// Parses the ALPN protocols of a ClientHello within a single TLS record.
+fn client_hello_alpn(record: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut reader = Reader(record);
+    if reader.u8()? != TLS_HANDSHAKE {
+        return None;
+    }
+    reader.take(2)?; // record version
+    let mut reader = Reader(reader.vec16()?);
+    if reader.u8()? != TLS_CLIENT_HELLO {
+        return None;
+    }
+    reader.take(3)?; // handshake length
+    reader.take(2 + 32)?; // client version and random
+    reader.vec8()?; // session id
+    reader.vec16()?; // cipher suites
+    reader.vec8()?; // compression methods
+    let mut extensions = Reader(reader.vec16()?);
+    while !extensions.0.is_empty() {
+        let ext_type = u16::from_be_bytes([extensions.u8()?, extensions.u8()?]);
+        let mut data = Reader(extensions.vec16()?);
+        if ext_type != TLS_EXT_ALPN {
+            continue;
+        }
+        let mut list = Reader(data.vec16()?);
+        let mut protocols = Vec::new();
+        while !list.0.is_empty() {
+            protocols.push(list.vec8()?);
+        }
+        return Some(protocols);
+    }
+    Some(Vec::new())
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|byte| byte[0])
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = u16::from_be_bytes([self.u8()?, self.u8()?]) as usize;
+        self.take(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+    use std::io::Read;
+    use tokio_rustls::rustls::CipherSuite;
+
+    /* ClientHellos of OpenSSL offering acme-tls/1, and h2 and http/1.1 */
+    const ACME_HELLO: &[u8] = include_bytes!("../tests/data/client-hello-acme.bin");
+    const H2_HELLO: &[u8] = include_bytes!("../tests/data/client-hello-h2.bin");
+
+    /// Stream which returns the data a few bytes at a time.
+    struct Chunks<'a>(&'a [u8], usize);
+
+    impl<'a> Read for Chunks<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = self.1.min(buf.len()).min(self.0.len());
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    impl<'a> AsyncRead for Chunks<'a> {}
+
+    #[test]
+    fn recorded_client_hellos() {
+        assert_eq!(client_hello_alpn(ACME_HELLO), Some(vec![ACME_TLS_ALPN]));
+        assert!(acme_challenge_offered(ACME_HELLO));
+        assert_eq!(
+            client_hello_alpn(H2_HELLO),
+            Some(vec![&b"h2"[..], &b"http/1.1"[..]])
+        );
+        assert!(!acme_challenge_offered(H2_HELLO));
+    }
+
+    #[test]
+    fn truncated_records() {
+        for len in 0..ACME_HELLO.len() {
+            assert_eq!(client_hello_alpn(&ACME_HELLO[..len]), None);
+            assert!(!acme_challenge_offered(&ACME_HELLO[..len]));
+        }
+    }
+
+    #[test]
+    fn bad_lengths() {
+        /* Any byte may be a length, none of them panics */
+        for hello in &[ACME_HELLO, H2_HELLO] {
+            for i in 0..hello.len() {
+                for value in &[0x00, 0x01, 0x7f, 0xff] {
+                    let mut record = hello.to_vec();
+                    record[i] = *value;
+                    let _ = acme_challenge_offered(&record);
+                }
+            }
+        }
+
+        /* Extensions longer than the ClientHello */
+        let mut record = H2_HELLO.to_vec();
+        let offset = extensions_offset(&record);
+        let len = record.len() as u16;
+        record[offset..offset + 2].copy_from_slice(&len.to_be_bytes());
+        assert_eq!(client_hello_alpn(&record), None);
+
+        /* A protocol longer than the ALPN extension */
+        let mut record = ACME_HELLO.to_vec();
+        let offset = record
+            .windows(ACME_TLS_ALPN.len())
+            .position(|window| window == ACME_TLS_ALPN)
+            .unwrap();
+        record[offset - 1] = 0xff;
+        assert_eq!(client_hello_alpn(&record), None);
+        assert!(!acme_challenge_offered(&record));
+    }
+
+    /// Returns the offset of the extensions length of a ClientHello record.
+    fn extensions_offset(record: &[u8]) -> usize {
+        let mut offset = TLS_RECORD_HDRLEN + 4 + 2 + 32;
+        offset += 1 + record[offset] as usize; // session id
+        offset += 2 + u16::from_be_bytes([record[offset], record[offset + 1]]) as usize; // cipher suites
+        offset += 1 + record[offset] as usize; // compression methods
+        offset
+    }
+
+    #[test]
+    fn client_hello_split_across_reads() {
+        for chunk in &[1, 7, 600] {
+            let (hello, _) = PeekClientHello::new(Chunks(ACME_HELLO, *chunk))
+                .wait()
+                .unwrap();
+            assert_eq!(hello, ACME_HELLO);
+            assert!(acme_challenge_offered(&hello));
+        }
+
+        let closed = PeekClientHello::new(Chunks(&ACME_HELLO[..100], 7)).wait();
+        assert_eq!(closed.err().unwrap().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    fn policy_config(min_version: &str, cipher_suites: &[&str]) -> Arc<ServerConfig> {
+        let storage = Storage::new(&StorageConfig::default());
+        let policy = TlsConfig {
+            min_version: min_version.to_string(),
+            cipher_suites: cipher_suites.iter().map(|name| name.to_string()).collect(),
+            ..TlsConfig::default()
+        };
+        server_config(Arc::new(CertResolver::new(Vec::new(), storage)), &policy)
+    }
+
+    #[test]
+    fn cipher_suite_names() {
+        assert_eq!(
+            cipher_suite("TLS13_AES_256_GCM_SHA384").map(|suite| suite.suite),
+            Some(CipherSuite::TLS13_AES_256_GCM_SHA384)
+        );
+        assert_eq!(
+            cipher_suite("TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256").map(|suite| suite.suite),
+            Some(CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256)
+        );
+        for suite in ALL_CIPHERSUITES.iter() {
+            let name = format!("{:?}", suite.suite);
+            assert_eq!(
+                cipher_suite(&name).map(|found| found.suite),
+                Some(suite.suite)
+            );
+        }
+        for name in &[
+            "",
+            "tls13_aes_256_gcm_sha384",
+            "TLS_RSA_WITH_RC4_128_MD5",
+            "AES256",
+        ] {
+            assert!(cipher_suite(name).is_none(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn min_version() {
+        assert_eq!(
+            policy_config("1.2", &[]).versions,
+            vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2]
+        );
+        assert_eq!(
+            policy_config("1.3", &[]).versions,
+            vec![ProtocolVersion::TLSv1_3]
+        );
+    }
+
+    #[test]
+    fn cipher_suite_policy() {
+        let config = policy_config("1.2", &[]);
+        assert_eq!(config.ciphersuites.len(), ALL_CIPHERSUITES.len());
+        assert!(!config.ignore_client_order);
+
+        let config = policy_config(
+            "1.2",
+            &[
+                "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+                "TLS13_AES_256_GCM_SHA384",
+            ],
+        );
+        let suites: Vec<CipherSuite> = config
+            .ciphersuites
+            .iter()
+            .map(|suite| suite.suite)
+            .collect();
+        assert_eq!(
+            suites,
+            vec![
+                CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+                CipherSuite::TLS13_AES_256_GCM_SHA384,
+            ]
+        );
+        assert!(config.ignore_client_order);
+    }
+}
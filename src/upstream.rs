@@ -0,0 +1,376 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ *
+ *  Copyright (C) 2020  Mles developers
+ */
+use bytes::BytesMut;
+use futures::future;
+use futures::{Future, Sink, Stream};
+use mles_utils::MsgHdr;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::io::{self, Error, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_io::codec::{Decoder as TokioDecoder, Encoder as TokioEncoder};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::{webpki, TlsConnector};
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::Message;
+use url::Url;
+
+use crate::config::{UpstreamConfig, UpstreamDialect};
+
+/// Subprotocol of Mles v2 servers
+const V2_PROTOCOL: &str = "mles-websocket";
+
+/// Encoded Msgs towards the Mles server.
+pub type FrameSink = Box<dyn Sink<SinkItem = Vec<u8>, SinkError = io::Error> + Send>;
+/// Encoded Msgs from the Mles server.
+pub type FrameStream = Box<dyn Stream<Item = Vec<u8>, Error = io::Error> + Send>;
+
+/// Connection to a Mles server which hides the framing of its dialect.
+pub struct Connection {
+    pub local_addr: SocketAddr,
+    pub sink: FrameSink,
+    pub stream: FrameStream,
+}
+
+/// Join of a Mles v2 channel
+#[derive(Serialize)]
+struct Join<'a> {
+    uid: &'a str,
+    channel: &'a str,
+}
+
+/// Connects to a Mles server of the upstream.
+pub fn connect(
+    upstream: &UpstreamConfig,
+    addr: SocketAddr,
+    tcp_keepalive: Duration,
+) -> Box<dyn Future<Item = Connection, Error = io::Error> + Send> {
+    let server_name = upstream.server_name.clone();
+    let tcp = TcpStream::connect(&addr).and_then(move |stream| {
+        stream.set_nodelay(true)?;
+        stream.set_keepalive(Some(tcp_keepalive))?;
+        Ok(stream)
+    });
+    match upstream.dialect {
+        UpstreamDialect::Legacy => Box::new(tcp.map(|stream| {
+            let local_addr = local_addr(&stream);
+            let (sink, stream) = Bytes.framed(stream).split();
+            Connection {
+                local_addr,
+                sink: Box::new(sink),
+                stream: Box::new(stream.map(|buf| buf.to_vec())),
+            }
+        })),
+        UpstreamDialect::V2 => {
+            Box::new(tcp.and_then(move |stream| connect_v2(stream, addr, &server_name)))
+        }
+    }
+}
+
+/// Returns the join frame which is sent first on a new connection. The
+/// legacy dialect joins with the first Msg of the client, which is sent
+/// again with the MsgHdr of the new connection once it has been sent.
+pub fn join_frame(
+    dialect: UpstreamDialect,
+    key: u64,
+    cid: u32,
+    uid: &str,
+    channel: &str,
+    first: Option<Vec<u8>>,
+) -> Option<Vec<u8>> {
+    match dialect {
+        UpstreamDialect::Legacy => first.map(|msg| frame(dialect, key, cid, msg)),
+        UpstreamDialect::V2 => serde_json::to_vec(&Join { uid, channel }).ok(),
+    }
+}
+
+/// Frames an encoded Msg for the Mles server.
+pub fn frame(dialect: UpstreamDialect, key: u64, cid: u32, msg: Vec<u8>) -> Vec<u8> {
+    match dialect {
+        UpstreamDialect::Legacy => {
+            let msghdr = MsgHdr::new(msg.len() as u32, cid, key);
+            let mut msgv = msghdr.encode();
+            msgv.extend(msg);
+            msgv
+        }
+        UpstreamDialect::V2 => msg,
+    }
+}
+
+fn local_addr(stream: &TcpStream) -> SocketAddr {
+    stream
+        .local_addr()
+        .unwrap_or_else(|_| SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0))
+}
+
+/// Mles v2 runs over WebSocket, with TLS if the server name is given.
+fn connect_v2(
+    stream: TcpStream,
+    addr: SocketAddr,
+    server_name: &str,
+) -> Box<dyn Future<Item = Connection, Error = io::Error> + Send> {
+    let local_addr = local_addr(&stream);
+    let to_connection = move |(sink, stream)| Connection {
+        local_addr,
+        sink,
+        stream,
+    };
+    if server_name.is_empty() {
+        let url = format!("ws://{}/", addr);
+        return Box::new(websocket(&url, stream).map(to_connection));
+    }
+    let dns_name = match webpki::DNSNameRef::try_from_ascii_str(server_name) {
+        Ok(dns_name) => dns_name,
+        Err(_) => {
+            return Box::new(future::err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is not a valid server name", server_name),
+            )))
+        }
+    };
+    let url = format!("wss://{}:{}/", server_name, addr.port());
+    let mut config = ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    let connector = TlsConnector::from(Arc::new(config));
+    Box::new(
+        connector
+            .connect(dns_name, stream)
+            .and_then(move |stream| websocket(&url, stream))
+            .map(to_connection),
+    )
+}
+
+/// WebSocket client whose first frame, the join, is sent as text and the
+/// rest as binary.
+fn websocket<S>(
+    url: &str,
+    stream: S,
+) -> Box<dyn Future<Item = (FrameSink, FrameStream), Error = io::Error> + Send>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let url = match Url::parse(url) {
+        Ok(url) => url,
+        Err(err) => return Box::new(future::err(Error::new(ErrorKind::InvalidInput, err))),
+    };
+    let mut request = Request::from(url);
+    request.add_protocol(Cow::Borrowed(V2_PROTOCOL));
+    Box::new(
+        tokio_tungstenite::client_async(request, stream)
+            .map_err(ws_err)
+            .map(|(websocket, _)| {
+                let (sink, stream) = websocket.split();
+                let mut joined = false;
+                let sink = sink.sink_map_err(ws_err).with(move |frame: Vec<u8>| {
+                    if joined {
+                        return Ok(Message::binary(frame));
+                    }
+                    joined = true;
+                    String::from_utf8(frame)
+                        .map(Message::text)
+                        .map_err(|err| Error::new(ErrorKind::InvalidData, err))
+                });
+                let stream = stream.map_err(ws_err).filter_map(|message| {
+                    if message.is_binary() {
+                        Some(message.into_data())
+                    } else {
+                        None
+                    }
+                });
+                (Box::new(sink) as FrameSink, Box::new(stream) as FrameStream)
+            }),
+    )
+}
+
+fn ws_err(err: tokio_tungstenite::tungstenite::Error) -> Error {
+    Error::new(ErrorKind::Other, err.to_string())
+}
+
+/// Framing of the legacy dialect, each Msg is preceded by its MsgHdr
+struct Bytes;
+
+impl TokioDecoder for Bytes {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        if buf.len() >= MsgHdr::get_hdrkey_len() {
+            let msghdr = MsgHdr::decode(buf.to_vec());
+            // HDRKEYL is header min size
+            if msghdr.get_type() != b'M' {
+                let len = buf.len();
+                buf.split_to(len);
+                return Ok(None);
+            }
+            let hdr_len = msghdr.get_len() as usize;
+            if 0 == hdr_len {
+                let len = buf.len();
+                buf.split_to(len);
+                return Ok(None);
+            }
+            let len = buf.len();
+            if len < (MsgHdr::get_hdrkey_len() + hdr_len) {
+                return Ok(None);
+            }
+            if MsgHdr::get_hdrkey_len() + hdr_len < len {
+                buf.split_to(MsgHdr::get_hdrkey_len());
+                return Ok(Some(buf.split_to(hdr_len)));
+            }
+            buf.split_to(MsgHdr::get_hdrkey_len());
+            return Ok(Some(buf.split_to(hdr_len)));
+        }
+        Ok(None)
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        self.decode(buf)
+    }
+}
+
+impl TokioEncoder for Bytes {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn encode(&mut self, data: Vec<u8>, buf: &mut BytesMut) -> io::Result<()> {
+        buf.extend_from_slice(&data[..]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request as Accept};
+
+    #[test]
+    fn join_frames() {
+        let join = join_frame(UpstreamDialect::V2, 1, 2, "alice", "a", None);
+        assert_eq!(join, Some(br#"{"uid":"alice","channel":"a"}"#.to_vec()));
+        let join = join_frame(UpstreamDialect::V2, 1, 2, "alice", "a", Some(vec![3]));
+        assert_eq!(join, Some(br#"{"uid":"alice","channel":"a"}"#.to_vec()));
+
+        assert_eq!(
+            join_frame(UpstreamDialect::Legacy, 1, 2, "alice", "a", None),
+            None
+        );
+        let join = join_frame(UpstreamDialect::Legacy, 1, 2, "alice", "a", Some(vec![3]));
+        assert_eq!(join, Some(frame(UpstreamDialect::Legacy, 1, 2, vec![3])));
+    }
+
+    #[test]
+    fn legacy_msghdr_layout() {
+        let framed = frame(
+            UpstreamDialect::Legacy,
+            0x0102_0304_0506_0708,
+            0x0a0b_0c0d,
+            vec![0xaa, 0xbb, 0xcc],
+        );
+        /* 'M', 24-bit length, cid and key in big endian, then the Msg */
+        assert_eq!(
+            framed,
+            vec![b'M', 0, 0, 3, 0x0a, 0x0b, 0x0c, 0x0d, 1, 2, 3, 4, 5, 6, 7, 8, 0xaa, 0xbb, 0xcc]
+        );
+        assert_eq!(framed, {
+            let mut baseline = MsgHdr::new(3, 0x0a0b_0c0d, 0x0102_0304_0506_0708).encode();
+            baseline.extend(vec![0xaa, 0xbb, 0xcc]);
+            baseline
+        });
+        assert_eq!(frame(UpstreamDialect::V2, 1, 2, vec![3]), vec![3]);
+    }
+
+    #[test]
+    fn legacy_decoder() {
+        let framed = frame(UpstreamDialect::Legacy, 1, 2, vec![0xaa, 0xbb, 0xcc]);
+        let mut buf = BytesMut::from(&framed[..framed.len() - 1]);
+        assert_eq!(Bytes.decode(&mut buf).unwrap(), None);
+        buf.extend_from_slice(&framed[framed.len() - 1..]);
+        buf.extend_from_slice(&framed);
+        assert_eq!(
+            Bytes.decode(&mut buf).unwrap().unwrap().to_vec(),
+            vec![0xaa, 0xbb, 0xcc]
+        );
+        assert_eq!(
+            Bytes.decode(&mut buf).unwrap().unwrap().to_vec(),
+            vec![0xaa, 0xbb, 0xcc]
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn v2_joins_first() {
+        let listener = tokio::net::TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let offered = Arc::new(Mutex::new(None));
+        let offered_inner = offered.clone();
+        let server = listener
+            .incoming()
+            .into_future()
+            .map_err(|(err, _)| err.to_string())
+            .and_then(move |(stream, _)| {
+                let callback = move |request: &Accept| {
+                    *offered_inner.lock().unwrap() = request
+                        .headers
+                        .find_first("Sec-WebSocket-Protocol")
+                        .map(|protocol| protocol.to_vec());
+                    let protocol = (
+                        "Sec-WebSocket-Protocol".to_string(),
+                        V2_PROTOCOL.to_string(),
+                    );
+                    Ok::<_, ErrorResponse>(Some(vec![protocol]))
+                };
+                tokio_tungstenite::accept_hdr_async(stream.unwrap(), callback)
+                    .map_err(|err| err.to_string())
+            })
+            .and_then(|websocket| {
+                websocket
+                    .send(Message::text("ignored"))
+                    .and_then(|websocket| websocket.send(Message::binary(&b"reply"[..])))
+                    .and_then(|websocket| websocket.take(2).collect())
+                    .map_err(|err| err.to_string())
+            });
+
+        let mut upstream = UpstreamConfig::default();
+        upstream.dialect = UpstreamDialect::V2;
+        let join = join_frame(UpstreamDialect::V2, 0, 0, "alice", "a", None).unwrap();
+        let client = connect(&upstream, addr, Duration::from_secs(60))
+            .and_then(move |conn| {
+                let Connection { sink, stream, .. } = conn;
+                sink.send(join)
+                    .and_then(|sink| sink.send(b"msg".to_vec()))
+                    .and_then(|sink| {
+                        stream
+                            .into_future()
+                            .map_err(|(err, _)| err)
+                            .map(move |(frame, stream)| (frame, sink, stream))
+                    })
+            })
+            .map_err(|err| err.to_string());
+
+        let mut rt = Runtime::new().unwrap();
+        let (messages, (frame, _, _)) = rt.block_on(server.join(client)).unwrap();
+        assert_eq!(
+            offered.lock().unwrap().as_ref().map(Vec::as_slice),
+            Some(V2_PROTOCOL.as_bytes())
+        );
+        assert_eq!(
+            messages,
+            vec![
+                Message::text(r#"{"uid":"alice","channel":"a"}"#),
+                Message::binary(b"msg".to_vec()),
+            ]
+        );
+        assert_eq!(frame, Some(b"reply".to_vec()));
+    }
+}